@@ -11,7 +11,7 @@
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -28,11 +28,98 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-env-changed=SLINT_FONT_SIZES");
+    println!("cargo:rerun-if-changed=lang");
 
+    write_glyphs(out);
+
+    // Glyphs are stored as distance fields, so each one is embedded once
+    // rather than once per font size.
     slint_build::compile_with_config(
         "ui/file.slint",
         slint_build::CompilerConfiguration::new()
-            .embed_resources(slint_build::EmbedResourcesKind::EmbedForSoftwareRenderer),
+            .embed_resources(slint_build::EmbedResourcesKind::EmbedForSoftwareRenderer)
+            .with_sdf_fonts(true)
+            .with_bundled_translations("lang")
+            .with_include_paths(vec![out.clone()]),
     )
     .unwrap();
 }
+
+// Writes `glyphs.slint`, holding every character in the ranges listed in
+// MENU_GLYPHS (e.g. "00A0-017F,0400-045F"). The UI's own strings and their
+// translations are picked up by Slint; these are for file names.
+//
+// DejaVu Sans has no CJK, so if the font named by MENU_CJK_FONT is there
+// (see ui/fonts/fetch-cjk.sh) it's imported too, along with the ranges in
+// MENU_CJK_GLYPHS and the characters in ui/fonts/cjk-glyphs.txt. Only
+// those glyphs are embedded, which keeps the flash used bounded however
+// big the font is.
+fn write_glyphs(out: &Path) {
+    let mut subset = String::new();
+    add_ranges(&mut subset, "MENU_GLYPHS");
+
+    let (mut import, mut cjk_family) = (String::new(), String::new());
+    println!("cargo:rerun-if-env-changed=MENU_CJK_FONT");
+    println!("cargo:rerun-if-env-changed=MENU_CJK_FAMILY");
+    if let Ok(font) = env::var("MENU_CJK_FONT") {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let font = manifest.join(font);
+        println!("cargo:rerun-if-changed={}", font.display());
+        if font.exists() {
+            import = format!("import \"{}\";\n", font.display());
+            cjk_family =
+                env::var("MENU_CJK_FAMILY").expect("MENU_CJK_FAMILY: needed with MENU_CJK_FONT");
+            add_ranges(&mut subset, "MENU_CJK_GLYPHS");
+            println!("cargo:rerun-if-changed=ui/fonts/cjk-glyphs.txt");
+            if let Ok(glyphs) = std::fs::read_to_string(manifest.join("ui/fonts/cjk-glyphs.txt")) {
+                let glyphs = glyphs.lines().filter(|line| !line.starts_with('#'));
+                glyphs
+                    .flat_map(str::chars)
+                    .for_each(|c| add_char(&mut subset, c));
+            }
+        } else {
+            println!(
+                "cargo:warning={} not found, so CJK names won't show; run ui/fonts/fetch-cjk.sh",
+                font.display()
+            );
+        }
+    }
+
+    File::create(out.join("glyphs.slint"))
+        .unwrap()
+        .write_all(
+            format!(
+                "{import}export global Glyphs {{\n    out property <string> subset: \"{subset}\";\n    out property <string> cjk-family: \"{cjk_family}\";\n}}\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+}
+
+// Adds the characters in the ranges listed in the environment variable `var`.
+fn add_ranges(subset: &mut String, var: &str) {
+    println!("cargo:rerun-if-env-changed={var}");
+    let ranges = env::var(var).unwrap_or_default();
+    for range in ranges.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let parse = |s: &str| {
+            u32::from_str_radix(s.trim(), 16)
+                .unwrap_or_else(|_| panic!("{var}: bad code point '{s}'"))
+        };
+        for c in (parse(start)..=parse(end)).filter_map(char::from_u32) {
+            add_char(subset, c);
+        }
+    }
+}
+
+fn add_char(subset: &mut String, c: char) {
+    match c {
+        '"' | '\\' => {
+            subset.push('\\');
+            subset.push(c);
+        }
+        c if !c.is_control() => subset.push(c),
+        _ => {}
+    }
+}
@@ -1,10 +1,20 @@
 MEMORY {
     BOOT2  : ORIGIN = 0x10000000, LENGTH = 0x100
     CONFIG : ORIGIN = 0x100FF000, LENGTH = 0x100
+    /* Settings kept by store.rs, just below the menu */
+    STORE  : ORIGIN = 0x10000000 + 2M - 704K, LENGTH = 64K
     FLASH  : ORIGIN = 0x10000000 + 2M - 640K, LENGTH = 576K
     RAM    : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
+/* For layout.rs, so the code works from the same numbers */
+__config_start = ORIGIN(CONFIG);
+__config_end = ORIGIN(CONFIG) + LENGTH(CONFIG);
+__store_start = ORIGIN(STORE);
+__store_end = ORIGIN(STORE) + LENGTH(STORE);
+__menu_start = ORIGIN(FLASH);
+__menu_end = ORIGIN(FLASH) + LENGTH(FLASH);
+
 EXTERN(BOOT2_FIRMWARE)
 EXTERN(CONFIG)
 
@@ -0,0 +1,355 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_sdmmc::{Error, SdCardError};
+use uf2_block::Block;
+
+use crate::sd::{SdFile, SpiSD};
+
+// The Pico SDK puts a header pointing at the binary_info entries somewhere
+// in the first 256 bytes after boot2, so picotool can find it.
+const FLASH_BASE: u32 = 0x1000_0000;
+const FLASH_SIZE: u32 = 2 * 1024 * 1024;
+const HEADER_SEARCH_START: u32 = FLASH_BASE + 0x100;
+const HEADER_SEARCH_LENGTH: usize = 256;
+const MARKER_START: u32 = 0x7188_ebf2;
+const MARKER_END: u32 = 0xe71a_a390;
+
+const TYPE_ID_AND_STRING: u16 = 6;
+const TYPE_PINS_WITH_FUNC: u16 = 8;
+const TYPE_PINS_WITH_NAME: u16 = 9;
+const TAG_RASPBERRY_PI: u16 = u16::from_le_bytes(*b"RP");
+
+const ID_PROGRAM_NAME: u32 = 0x0203_1c86;
+const ID_PROGRAM_VERSION: u32 = 0x11a9_bc3a;
+const ID_PROGRAM_BUILD_DATE: u32 = 0x9da2_2254;
+const ID_PROGRAM_URL: u32 = 0x1856_239a;
+const ID_PROGRAM_DESCRIPTION: u32 = 0xb6a0_7c19;
+
+const PINS_ENCODING_RANGE: u32 = 1;
+const PINS_ENCODING_MULTI: u32 = 2;
+// RP2040 GPIO functions, as numbered in the pin encoding
+const PIN_FUNCTIONS: [&str; 10] = [
+    "XIP", "SPI", "UART", "I2C", "PWM", "SIO", "PIO0", "PIO1", "GPCK", "USB",
+];
+
+// Limits, in case what looks like a header is really something else
+const MAX_ENTRIES: u32 = 64;
+const MAX_MAPPINGS: u32 = 8;
+const MAX_STRING: usize = 96;
+const UF2_BLOCK_LENGTH: usize = 512;
+
+/// What an image built with the Pico SDK or `rp-binary-info` says about
+/// itself.
+#[derive(Clone, Default)]
+pub struct BinaryInfo {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub build_date: Option<String>,
+    pub url: Option<String>,
+    pub description: Option<String>,
+    // One line per pin or group of pins, e.g. "GP0, GP1: UART"
+    pub pins: Vec<String>,
+}
+
+impl BinaryInfo {
+    /// The program name and version, for showing in a list.
+    pub fn title(&self) -> Option<String> {
+        let name = self.name.as_ref()?;
+        Some(match &self.version {
+            Some(version) => alloc::format!("{} {}", name, version),
+            None => name.clone(),
+        })
+    }
+}
+
+/// Reads binary_info from a UF2 file on the card. Images without any give
+/// `Ok(None)`.
+pub fn from_uf2(sd: &SpiSD<'_>, filename: &str) -> Result<Option<BinaryInfo>, Error<SdCardError>> {
+    let mut info = None;
+    sd.open(filename, |file| {
+        info = parse(&mut Uf2Image::new(file));
+    })?;
+    Ok(info)
+}
+
+/// `len` bytes of flash from `address`, read through XIP, or `None` if
+/// that's not all flash.
+pub fn xip(address: u32, len: usize) -> Option<&'static [u8]> {
+    let end = address as u64 + len as u64;
+    if address < FLASH_BASE || end > (FLASH_BASE + FLASH_SIZE) as u64 {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(address as *const u8, len) })
+}
+
+/// Reads binary_info from the app installed in flash.
+pub fn installed() -> Option<BinaryInfo> {
+    parse(&mut Xip)
+}
+
+// Somewhere to read an image's memory from. Addresses come from the image
+// itself, so arithmetic on them wraps, and reads from a wrapped address fail.
+trait Image {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> bool;
+
+    fn word(&mut self, address: u32) -> Option<u32> {
+        let mut buf = [0u8; 4];
+        self.read(address, &mut buf)
+            .then(|| u32::from_le_bytes(buf))
+    }
+}
+
+// The installed app, read straight from XIP flash.
+struct Xip;
+
+impl Image for Xip {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> bool {
+        let Some(flash) = xip(address, buf.len()) else {
+            return false;
+        };
+        buf.copy_from_slice(flash);
+        true
+    }
+}
+
+// A UF2 file, found block by block from the target addresses.
+struct Uf2Image<'f, 'a, 'spi> {
+    file: &'f SdFile<'a, 'spi>,
+    // Target address of the first block, for guessing where others are
+    base: Option<u32>,
+    // Target addresses of every block, sorted, once the guess has failed
+    map: Option<Vec<(u32, u32)>>,
+    // The last block read: its target address and payload
+    current: Option<(u32, Vec<u8>)>,
+}
+
+impl<'f, 'a, 'spi> Uf2Image<'f, 'a, 'spi> {
+    fn new(file: &'f SdFile<'a, 'spi>) -> Self {
+        let mut image = Uf2Image {
+            file,
+            base: None,
+            map: None,
+            current: None,
+        };
+        image.base = image.read_block(0).map(|(address, _)| address);
+        image
+    }
+
+    fn read_block(&self, index: u32) -> Option<(u32, Vec<u8>)> {
+        let mut buf = [0u8; UF2_BLOCK_LENGTH];
+        self.file
+            .seek_from_start(index.checked_mul(UF2_BLOCK_LENGTH as u32)?)
+            .ok()?;
+        let mut len = 0;
+        while len < buf.len() {
+            match self.file.read(&mut buf[len..]) {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => len += n,
+            }
+        }
+        let block = Block::parse(&buf).ok()?;
+        let data = block.data.get(..block.payload_size as usize)?;
+        Some((block.target_address, data.to_vec()))
+    }
+
+    // Finds the block holding `address`. Images are nearly always written
+    // as consecutive 256-byte blocks, so that's tried before reading the
+    // whole block map.
+    fn find_block(&mut self, address: u32) -> Option<(u32, Vec<u8>)> {
+        let base = self.base?;
+        if self.map.is_none() && address >= base {
+            let guess = (address - base) / 256;
+            if let Some((start, data)) = self.read_block(guess) {
+                if (start..start.wrapping_add(data.len() as u32)).contains(&address) {
+                    return Some((start, data));
+                }
+            }
+        }
+        if self.map.is_none() {
+            let blocks = self.file.length() / UF2_BLOCK_LENGTH as u32;
+            let mut map: Vec<(u32, u32)> = (0..blocks)
+                .filter_map(|index| self.read_block(index).map(|(start, _)| (start, index)))
+                .collect();
+            map.sort_unstable();
+            self.map = Some(map);
+        }
+        let map = self.map.as_ref()?;
+        let index = match map.binary_search_by_key(&address, |&(start, _)| start) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let (start, data) = self.read_block(map[index].1)?;
+        (start..start.wrapping_add(data.len() as u32))
+            .contains(&address)
+            .then_some((start, data))
+    }
+}
+
+impl Image for Uf2Image<'_, '_, '_> {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> bool {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = address.wrapping_add(done as u32);
+            let cached = self.current.as_ref().is_some_and(|(start, data)| {
+                (*start..start.wrapping_add(data.len() as u32)).contains(&at)
+            });
+            if !cached {
+                match self.find_block(at) {
+                    Some(block) => self.current = Some(block),
+                    None => return false,
+                }
+            }
+            let (start, data) = self.current.as_ref().unwrap();
+            let offset = (at - start) as usize;
+            let len = (data.len() - offset).min(buf.len() - done);
+            buf[done..done + len].copy_from_slice(&data[offset..offset + len]);
+            done += len;
+        }
+        true
+    }
+}
+
+fn parse(image: &mut impl Image) -> Option<BinaryInfo> {
+    let mut header = vec![0u8; HEADER_SEARCH_LENGTH];
+    if !image.read(HEADER_SEARCH_START, &mut header) {
+        return None;
+    }
+    let word = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let offset = (0..=HEADER_SEARCH_LENGTH - 20)
+        .step_by(4)
+        .find(|&offset| word(offset) == MARKER_START && word(offset + 16) == MARKER_END)?;
+    let (start, end, mapping) = (word(offset + 4), word(offset + 8), word(offset + 12));
+
+    // Entries may point at data that's copied to RAM at startup
+    let mut mappings = Vec::new();
+    for index in 0..MAX_MAPPINGS {
+        let entry = mapping.wrapping_add(index * 12);
+        let source = image.word(entry)?;
+        if source == 0 {
+            break;
+        }
+        mappings.push((
+            source,
+            image.word(entry.wrapping_add(4))?,
+            image.word(entry.wrapping_add(8))?,
+        ));
+    }
+    let translate = |address: u32| {
+        mappings
+            .iter()
+            .find(|(_, dest, dest_end)| (*dest..*dest_end).contains(&address))
+            .map_or(address, |(source, dest, _)| {
+                source.wrapping_add(address - dest)
+            })
+    };
+
+    let mut info = BinaryInfo::default();
+    let count = (end.saturating_sub(start) / 4).min(MAX_ENTRIES);
+    for index in 0..count {
+        let Some(entry) = image.word(start.wrapping_add(index * 4)).map(translate) else {
+            continue;
+        };
+        let Some(core) = image.word(entry) else {
+            continue;
+        };
+        let (kind, tag) = (core as u16, (core >> 16) as u16);
+        if tag != TAG_RASPBERRY_PI {
+            continue;
+        }
+        match kind {
+            TYPE_ID_AND_STRING => {
+                let (Some(id), Some(value)) = (
+                    image.word(entry.wrapping_add(4)),
+                    image.word(entry.wrapping_add(8)),
+                ) else {
+                    continue;
+                };
+                let value = read_string(image, translate(value));
+                match id {
+                    ID_PROGRAM_NAME => info.name = value,
+                    ID_PROGRAM_VERSION => info.version = value,
+                    ID_PROGRAM_BUILD_DATE => info.build_date = value,
+                    ID_PROGRAM_URL => info.url = value,
+                    ID_PROGRAM_DESCRIPTION => info.description = value,
+                    _ => {}
+                }
+            }
+            TYPE_PINS_WITH_FUNC => {
+                if let Some(pins) = image.word(entry.wrapping_add(4)).and_then(pins_with_func) {
+                    info.pins.push(pins);
+                }
+            }
+            TYPE_PINS_WITH_NAME => {
+                let (Some(mask), Some(label)) = (
+                    image.word(entry.wrapping_add(4)),
+                    image.word(entry.wrapping_add(8)),
+                ) else {
+                    continue;
+                };
+                if let Some(label) = read_string(image, translate(label)) {
+                    info.pins
+                        .push(alloc::format!("{}: {}", pin_list(mask), label));
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(info)
+}
+
+fn read_string(image: &mut impl Image, address: u32) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 16];
+    while bytes.len() < MAX_STRING {
+        if !image.read(address.wrapping_add(bytes.len() as u32), &mut chunk) {
+            break;
+        }
+        match chunk.iter().position(|&b| b == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                return String::from_utf8(bytes).ok();
+            }
+            None => bytes.extend_from_slice(&chunk),
+        }
+    }
+    None
+}
+
+// Pins and their function, packed as in the SDK's bi_*_with_func macros.
+fn pins_with_func(encoding: u32) -> Option<String> {
+    let function = PIN_FUNCTIONS.get(((encoding >> 3) & 0xf) as usize)?;
+    let pin = |n: u32| (encoding >> (7 + n * 5)) & 0x1f;
+    let mut mask = 0u32;
+    match encoding & 7 {
+        PINS_ENCODING_RANGE => {
+            for p in pin(0)..=pin(1) {
+                mask |= 1 << p;
+            }
+        }
+        PINS_ENCODING_MULTI => {
+            // The list ends where a pin repeats the one before
+            mask |= 1 << pin(0);
+            for n in 1..5 {
+                if pin(n) == pin(n - 1) {
+                    break;
+                }
+                mask |= 1 << pin(n);
+            }
+        }
+        _ => return None,
+    }
+    Some(alloc::format!("{}: {}", pin_list(mask), function))
+}
+
+fn pin_list(mask: u32) -> String {
+    let mut list = String::new();
+    for pin in (0..32).filter(|p| mask & (1 << p) != 0) {
+        if !list.is_empty() {
+            list.push_str(", ");
+        }
+        list.push_str(&alloc::format!("GP{}", pin));
+    }
+    list
+}
@@ -0,0 +1,101 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_sdmmc::{Error, SdCardError};
+
+use crate::sd::SpiSD;
+
+pub const EXTENSION: &str = ".list";
+const MAX_SIZE: usize = 4096;
+
+pub struct Item {
+    // From the root of the volume
+    pub path: String,
+    pub title: Option<String>,
+}
+
+/// A `.list` file, shown as a folder of the images it names, e.g.
+///
+/// ```text
+/// # Demo kit
+/// /games/invaders.uf2 = Space Invaders
+/// ../tools/blink.uf2
+/// ```
+///
+/// Paths without a leading '/' are relative to the directory holding the
+/// list.
+pub struct Collection {
+    // The list file's name, e.g. "Demo kit.list"
+    pub filename: String,
+    pub items: Vec<Item>,
+}
+
+impl Collection {
+    pub fn load(sd: &SpiSD<'_>, filename: &str) -> Result<Collection, Error<SdCardError>> {
+        let mut buf = vec![0u8; MAX_SIZE];
+        let len = sd.read_file(filename, &mut buf)?;
+        let text =
+            core::str::from_utf8(&buf[..len]).map_err(|_| Error::FormatError("not valid UTF-8"))?;
+        let dir = sd.dir_path();
+        let items = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (path, title) = match line.split_once('=') {
+                    Some((path, title)) => (path.trim(), Some(title.trim().into())),
+                    None => (line, None),
+                };
+                Item {
+                    path: resolve(&dir, path),
+                    title,
+                }
+            })
+            .collect();
+        Ok(Collection {
+            filename: filename.into(),
+            items,
+        })
+    }
+
+    /// The name shown for the collection, which is its file name without
+    /// `.list`.
+    pub fn name(&self) -> &str {
+        strip_extension(&self.filename).unwrap_or(&self.filename)
+    }
+
+    pub fn find(&self, path: &str) -> Option<&Item> {
+        self.items.iter().find(|item| item.path == path)
+    }
+}
+
+/// The name of a collection file without its extension, or `None` if it
+/// isn't one.
+pub fn strip_extension(filename: &str) -> Option<&str> {
+    let split = filename.len().checked_sub(EXTENSION.len())?;
+    let (name, extension) = filename.split_at_checked(split)?;
+    extension.eq_ignore_ascii_case(EXTENSION).then_some(name)
+}
+
+// Makes `path` absolute, resolving "." and ".." against `dir`.
+fn resolve(dir: &str, path: &str) -> String {
+    let mut components: Vec<&str> = match path.starts_with('/') {
+        true => Vec::new(),
+        false => dir.split('/').filter(|c| !c.is_empty()).collect(),
+    };
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    let mut resolved = String::new();
+    for component in components {
+        resolved.push('/');
+        resolved.push_str(component);
+    }
+    resolved
+}
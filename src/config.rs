@@ -1 +1,27 @@
 pub static CONFIG_ILI9341: [u8; 256] = *include_bytes!("../bin/config-ili9341.bin");
+
+// The CONFIG block uses MakeCode Arcade's layout: two magic words, then
+// pairs of little-endian u32 keys and values, ending with a zero key.
+const MAGIC: [u32; 2] = [0x1e9e_10f1, 0x2022_7a79];
+
+pub const DISPLAY_WIDTH: u32 = 0x25;
+pub const DISPLAY_HEIGHT: u32 = 0x26;
+
+/// Looks up a value in a config block.
+pub fn lookup(config: &[u8], key: u32) -> Option<u32> {
+    let mut words = config
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+    if words.next()? != MAGIC[0] || words.next()? != MAGIC[1] {
+        return None;
+    }
+    while let (Some(k), Some(v)) = (words.next(), words.next()) {
+        if k == 0 {
+            break;
+        }
+        if k == key {
+            return Some(v);
+        }
+    }
+    None
+}
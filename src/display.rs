@@ -8,41 +8,107 @@ use panic_probe as _;
 
 use alloc::boxed::Box;
 use alloc::vec;
-use alloc::vec::Vec;
+#[cfg(feature = "line-buffer")]
+use core::{
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
 
 use embassy_rp::{
     gpio::{Level, Output},
     peripherals::SPI0,
+    pwm::{Config as PwmConfig, Pwm},
     spi::{Async, Config as SpiConfig, Spi},
 };
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
 use lcd_async::{
     Builder, Display as LcdDisplay, interface,
     models::ILI9342CRgb565,
     options::{ColorInversion, Orientation, Rotation},
 };
-use slint::platform::software_renderer::{Rgb565Pixel, TargetPixel};
+use slint::platform::software_renderer::Rgb565Pixel;
+#[cfg(feature = "line-buffer")]
+use slint::platform::software_renderer::{LineBufferProvider, SoftwareRenderer};
+#[cfg(not(feature = "line-buffer"))]
+use slint::platform::software_renderer::{PhysicalRegion, TargetPixel};
 use static_cell::StaticCell;
 
-use crate::{DisplayResources, ui::TargetPixelType};
+use crate::store::{self, Store};
+use crate::{CONFIG, DisplayResources, config, ui::TargetPixelType};
+
+// The most pixels the heap has room for. Bigger panels show a band across
+// the middle, e.g. 480x160 of a 480x320 panel, and the UI is laid out for
+// the band. A 480x320 panel needs the `line-buffer` feature to be used whole.
+pub const MAX_FRAME_SIZE: usize = 320 * 240;
 
-macro_rules! box_array {
-    ($val:expr ; $len:expr) => {{
-        // Use a generic function so that the pointer cast remains type-safe
-        fn vec_to_boxed_array<T>(vec: Vec<T>) -> Box<[T; $len]> {
-            let boxed_slice = vec.into_boxed_slice();
+// Lines rendered before they're sent, in each of the two line buffers
+#[cfg(feature = "line-buffer")]
+const LINE_ROWS: usize = 4;
+// The widest the UI is laid out for when rendering by line. Wider panels
+// show a band down the middle.
+#[cfg(feature = "line-buffer")]
+const MAX_LINE_WIDTH: usize = 480;
+// Bytes taken by the two line buffers
+#[cfg(feature = "line-buffer")]
+pub const LINE_BUFFERS_SIZE: usize =
+    2 * MAX_LINE_WIDTH * LINE_ROWS * core::mem::size_of::<TargetPixelType>();
+
+/// The panel's size and how it's mounted, from the CONFIG block unless the
+/// settings file has overridden it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    // The panel's own width and height, before rotation
+    pub width: u16,
+    pub height: u16,
+    // Degrees clockwise: 0, 90, 180 or 270
+    pub rotation: u16,
+}
 
-            let ptr = Box::into_raw(boxed_slice) as *mut [T; $len];
+// The sides the settings file accepts. Anything else in the store or the
+// CONFIG block is taken to be a mistake, and the default is used instead.
+const SIDES: core::ops::RangeInclusive<u16> = 64..=1024;
 
-            unsafe { Box::from_raw(ptr) }
+impl Geometry {
+    pub fn load(store: &Store) -> Geometry {
+        let side = |key, config_key, default| {
+            store
+                .get(key)
+                .or_else(|| config::lookup(&CONFIG, config_key).and_then(|v| u16::try_from(v).ok()))
+                .filter(|side| SIDES.contains(side))
+                .unwrap_or(default)
+        };
+        Geometry {
+            width: side(&store::DISPLAY_WIDTH, config::DISPLAY_WIDTH, 320),
+            height: side(&store::DISPLAY_HEIGHT, config::DISPLAY_HEIGHT, 240),
+            rotation: store
+                .get(&store::DISPLAY_ROTATION)
+                .filter(|rotation| matches!(rotation, 0 | 90 | 180 | 270))
+                .unwrap_or(0),
         }
+    }
 
-        vec_to_boxed_array(vec![$val; $len])
-    }};
-}
+    /// The size the UI is laid out for.
+    pub fn size(&self) -> (usize, usize) {
+        let (w, h) = (self.width as usize, self.height as usize);
+        if self.rotation % 180 == 90 {
+            (h, w)
+        } else {
+            (w, h)
+        }
+    }
 
-pub const WIDTH: usize = 320;
-pub const HEIGHT: usize = 240;
-pub const FRAME_SIZE: usize = WIDTH * HEIGHT;
+    fn lcd_rotation(&self) -> Rotation {
+        // The ILI9342C is the right way up at 90°
+        match (self.rotation + 90) % 360 {
+            0 => Rotation::Deg0,
+            90 => Rotation::Deg90,
+            180 => Rotation::Deg180,
+            _ => Rotation::Deg270,
+        }
+    }
+}
 
 type SpiInterface<'spi> = interface::SpiInterface<
     ExclusiveDevice<Spi<'spi, SPI0, Async>, Output<'spi>, Delay>,
@@ -56,16 +122,32 @@ pub type DisplayError = interface::SpiError<
     Infallible,
 >;
 
+// Backlight level in percent, applied by the render loop.
+pub static BRIGHTNESS: Signal<ThreadModeRawMutex, u8> = Signal::new();
+
+const BACKLIGHT_TOP: u16 = 0x0fff;
+
 pub struct Display<'spi> {
     display: SpiDisplay<'spi>,
-    backlight: Output<'spi>,
-    framebuffer: &'spi mut Box<[TargetPixelType; FRAME_SIZE]>,
+    backlight: Pwm<'spi>,
+    brightness: u8,
+    #[cfg(not(feature = "line-buffer"))]
+    framebuffer: &'spi mut Box<[TargetPixelType]>,
+    // One buffer is drawn into while the other is sent
+    #[cfg(feature = "line-buffer")]
+    lines: [Box<[TargetPixelType]>; 2],
+    // Where the framebuffer goes on the panel, and its size
+    x: u16,
+    y: u16,
+    width: usize,
+    height: usize,
 }
 
-static FB: StaticCell<Box<[TargetPixelType; FRAME_SIZE]>> = StaticCell::new();
+#[cfg(not(feature = "line-buffer"))]
+static FB: StaticCell<Box<[TargetPixelType]>> = StaticCell::new();
 
 impl<'spi> Display<'spi> {
-    pub async fn new(res: DisplayResources) -> Display<'spi> {
+    pub async fn new(res: DisplayResources, geometry: Geometry) -> Display<'spi> {
         let mut spi_cfg = SpiConfig::default();
         spi_cfg.frequency = 125_000_000;
         spi_cfg.polarity = MODE_0.polarity;
@@ -84,10 +166,10 @@ impl<'spi> Display<'spi> {
         let mut delay = embassy_time::Delay;
         let display = Builder::new(ILI9342CRgb565, di)
             .reset_pin(rst)
-            .display_size(WIDTH as u16, HEIGHT as u16)
+            .display_size(geometry.width, geometry.height)
             .invert_colors(ColorInversion::Normal)
             .orientation(Orientation {
-                rotation: Rotation::Deg90,
+                rotation: geometry.lcd_rotation(),
                 mirrored: true,
             })
             .display_offset(0, 0)
@@ -95,35 +177,260 @@ impl<'spi> Display<'spi> {
             .await
             .expect("creating display");
 
-        let backlight = Output::new(res.backlight, Level::Low);
+        // Off until backlight() is called
+        let mut pwm_cfg = PwmConfig::default();
+        pwm_cfg.top = BACKLIGHT_TOP;
+        pwm_cfg.compare_a = 0;
+        let backlight = Pwm::new_output_a(res.pwm, res.backlight, pwm_cfg);
+
+        let (panel_width, panel_height) = geometry.size();
+        #[cfg(not(feature = "line-buffer"))]
+        let (width, height) = (panel_width, panel_height.min(MAX_FRAME_SIZE / panel_width));
+        #[cfg(not(feature = "line-buffer"))]
+        let framebuffer: &'static mut Box<[Rgb565Pixel]> = FB
+            .init(vec![Rgb565Pixel::from_rgb(255u8, 0u8, 0u8); width * height].into_boxed_slice());
 
-        let framebuffer: &'static mut Box<[Rgb565Pixel; _]> =
-            FB.init(box_array! [Rgb565Pixel::from_rgb(255u8, 0u8, 0u8); FRAME_SIZE]);
+        #[cfg(feature = "line-buffer")]
+        let (width, height) = (panel_width.min(MAX_LINE_WIDTH), panel_height);
+        #[cfg(feature = "line-buffer")]
+        let lines = [
+            vec![Rgb565Pixel::default(); width * LINE_ROWS].into_boxed_slice(),
+            vec![Rgb565Pixel::default(); width * LINE_ROWS].into_boxed_slice(),
+        ];
 
         Display {
             display,
             backlight,
+            brightness: 100,
+            #[cfg(not(feature = "line-buffer"))]
             framebuffer,
+            #[cfg(feature = "line-buffer")]
+            lines,
+            x: ((panel_width - width) / 2) as u16,
+            y: ((panel_height - height) / 2) as u16,
+            width,
+            height,
         }
     }
 
     pub async fn backlight(&mut self, on: bool) {
-        if on {
-            self.backlight.set_high()
-        } else {
-            self.backlight.set_low();
+        let percent = if on { self.brightness } else { 0 };
+        self.set_duty(percent);
+    }
+
+    pub fn set_brightness(&mut self, percent: u8) {
+        self.brightness = percent.min(100);
+        self.set_duty(self.brightness);
+    }
+
+    fn set_duty(&mut self, percent: u8) {
+        let mut pwm_cfg = PwmConfig::default();
+        pwm_cfg.top = BACKLIGHT_TOP;
+        pwm_cfg.compare_a = (BACKLIGHT_TOP as u32 * percent as u32 / 100) as u16;
+        self.backlight.set_config(&pwm_cfg);
+    }
+
+    /// Sends the parts of the framebuffer that the renderer says have
+    /// changed.
+    #[cfg(not(feature = "line-buffer"))]
+    pub async fn draw(&mut self, region: &PhysicalRegion) -> Result<(), DisplayError> {
+        for (origin, size) in region.iter() {
+            let x = (origin.x.max(0) as usize).min(self.width);
+            let y = (origin.y.max(0) as usize).min(self.height);
+            let width = (size.width as usize).min(self.width - x);
+            let height = (size.height as usize).min(self.height - y);
+            if width == 0 || height == 0 {
+                continue;
+            }
+            // Rows of a band across the whole framebuffer follow each other,
+            // so wide rectangles go in one transfer and narrow ones a row at
+            // a time
+            if width * 2 > self.width {
+                self.send(0, y, self.width, height).await?;
+            } else {
+                for row in y..y + height {
+                    self.send(x, row, width, 1).await?;
+                }
+            }
         }
+        Ok(())
     }
 
-    pub async fn draw(&mut self) -> Result<(), DisplayError> {
-        let ptr = self.framebuffer.as_ptr().cast::<[u8; FRAME_SIZE * 2]>();
+    // Sends `height` rows of `width` pixels from (x, y) in the framebuffer,
+    // which must be contiguous: a whole-width band or a single row.
+    #[cfg(not(feature = "line-buffer"))]
+    async fn send(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<(), DisplayError> {
+        let start = y * self.width + x;
+        let pixels = &self.framebuffer[start..start + (height - 1) * self.width + width];
+        let data =
+            unsafe { core::slice::from_raw_parts(pixels.as_ptr().cast::<u8>(), pixels.len() * 2) };
         self.display
-            .show_raw_data(0, 0, WIDTH as u16, HEIGHT as u16, unsafe { &*ptr })
+            .show_raw_data(
+                self.x + x as u16,
+                self.y + y as u16,
+                width as u16,
+                height as u16,
+                data,
+            )
             .await?;
         Ok(())
     }
 
-    pub fn borrow_framebuffer_mut(&mut self) -> &mut [TargetPixelType; FRAME_SIZE] {
+    /// The size of the framebuffer, which the UI is laid out for.
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    #[cfg(not(feature = "line-buffer"))]
+    pub fn borrow_framebuffer_mut(&mut self) -> &mut [TargetPixelType] {
         self.framebuffer
     }
+
+    /// Renders what has changed a few lines at a time, sending each batch
+    /// of lines while the next is drawn. This blocks the executor until the
+    /// last batch is sent: Slint renders by line synchronously, so there's
+    /// nowhere to yield. A full frame takes tens of milliseconds.
+    #[cfg(feature = "line-buffer")]
+    pub fn render(&mut self, renderer: &SoftwareRenderer) -> Result<(), DisplayError> {
+        let [first, second] = &mut self.lines;
+        let mut sender = LineSender {
+            display: Some(&mut self.display),
+            filling: Some(&mut first[..]),
+            spare: Some(&mut second[..]),
+            sending: None,
+            origin: (self.x, self.y),
+            line: 0,
+            range: 0..0,
+            rows: 0,
+            result: Ok(()),
+        };
+        renderer.render_by_line(&mut sender);
+        sender.flush();
+        sender.wait();
+        sender.result
+    }
+}
+
+// The display and line buffer handed back once a batch has been sent
+#[cfg(feature = "line-buffer")]
+type Sent<'a, 'spi> = (
+    &'a mut SpiDisplay<'spi>,
+    &'a mut [TargetPixelType],
+    Result<(), DisplayError>,
+);
+
+// Collects rendered lines into batches with the same columns, and sends
+// each batch by DMA while the renderer carries on.
+#[cfg(feature = "line-buffer")]
+struct LineSender<'a, 'spi> {
+    // Each of these is away while a batch is being sent
+    display: Option<&'a mut SpiDisplay<'spi>>,
+    filling: Option<&'a mut [TargetPixelType]>,
+    spare: Option<&'a mut [TargetPixelType]>,
+    sending: Option<Pin<Box<dyn Future<Output = Sent<'a, 'spi>> + 'a>>>,
+    // Where the UI's top left corner is on the panel
+    origin: (u16, u16),
+    // The batch being filled: its first line, columns, and lines so far
+    line: usize,
+    range: Range<usize>,
+    rows: usize,
+    // The first error, if sending failed
+    result: Result<(), DisplayError>,
+}
+
+#[cfg(feature = "line-buffer")]
+impl<'a, 'spi: 'a> LineSender<'a, 'spi> {
+    // Starts sending the lines collected so far.
+    fn flush(&mut self) {
+        if self.rows == 0 {
+            return;
+        }
+        // Only one batch is sent at a time
+        self.wait();
+        let (Some(display), Some(buffer)) = (self.display.take(), self.filling.take()) else {
+            return;
+        };
+        let x = self.origin.0 + self.range.start as u16;
+        let y = self.origin.1 + self.line as u16;
+        let (width, height) = (self.range.len(), self.rows);
+        self.sending = Some(Box::pin(async move {
+            let result = {
+                let pixels = &buffer[..width * height];
+                let data = unsafe {
+                    core::slice::from_raw_parts(pixels.as_ptr().cast::<u8>(), pixels.len() * 2)
+                };
+                display
+                    .show_raw_data(x, y, width as u16, height as u16, data)
+                    .await
+                    .map_err(DisplayError::from)
+            };
+            (display, buffer, result)
+        }));
+        self.poll();
+        self.filling = self.spare.take();
+        self.rows = 0;
+    }
+
+    // Moves the batch being sent along, without waiting for it.
+    fn poll(&mut self) {
+        let Some(sending) = self.sending.as_mut() else {
+            return;
+        };
+        let mut cx = Context::from_waker(Waker::noop());
+        if let Poll::Ready(sent) = sending.as_mut().poll(&mut cx) {
+            self.sending = None;
+            self.sent(sent);
+        }
+    }
+
+    // Waits for the batch being sent to finish, spinning rather than
+    // yielding to other tasks.
+    fn wait(&mut self) {
+        if let Some(sending) = self.sending.take() {
+            let sent = embassy_futures::block_on(sending);
+            self.sent(sent);
+        }
+    }
+
+    fn sent(&mut self, (display, buffer, result): Sent<'a, 'spi>) {
+        self.display = Some(display);
+        self.spare = Some(buffer);
+        if self.result.is_ok() {
+            self.result = result;
+        }
+    }
+}
+
+#[cfg(feature = "line-buffer")]
+impl<'a, 'spi: 'a> LineBufferProvider for &mut LineSender<'a, 'spi> {
+    type TargetPixel = TargetPixelType;
+
+    fn process_line(
+        &mut self,
+        line: usize,
+        range: Range<usize>,
+        render_fn: impl FnOnce(&mut [TargetPixelType]),
+    ) {
+        let follows = range == self.range && line == self.line + self.rows;
+        if !follows || self.rows == LINE_ROWS {
+            self.flush();
+        }
+        if self.rows == 0 {
+            self.line = line;
+            self.range = range.clone();
+        }
+        self.poll();
+        let width = range.len();
+        let Some(buffer) = self.filling.as_mut() else {
+            return;
+        };
+        render_fn(&mut buffer[self.rows * width..(self.rows + 1) * width]);
+        self.rows += 1;
+    }
 }
@@ -0,0 +1,143 @@
+use core::fmt::Write;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use defmt::*;
+use embassy_rp::rtc::DateTime;
+use embedded_sdmmc::{Error, SdCardError};
+
+use crate::{rtc, sd::SpiSD};
+
+const HISTORY_FILE: &str = "APPS.TXT";
+const HISTORY_SIZE: usize = 8192;
+
+// Unpinned entries beyond this are forgotten, oldest first.
+const MAX_RECENTS: usize = 24;
+
+pub struct LaunchRecord {
+    pub volume: usize,
+    pub name: String,
+    pub launches: u32,
+    pub last_launched: Option<DateTime>,
+    pub pinned: bool,
+}
+
+/// Launch counts and favourites, kept in the menu's state folder on the
+/// card, most recently launched first.
+#[derive(Default)]
+pub struct History {
+    records: Vec<LaunchRecord>,
+}
+
+impl History {
+    pub fn load(sd: &SpiSD<'_>) -> History {
+        let mut buf = vec![0u8; HISTORY_SIZE];
+        let len = match sd.read_state(HISTORY_FILE, &mut buf) {
+            Ok(len) => len,
+            Err(Error::NotFound) => 0,
+            Err(e) => {
+                warn!("history: failed to read: {}", e);
+                0
+            }
+        };
+        let text = core::str::from_utf8(&buf[..len]).unwrap_or("");
+        History {
+            records: text.lines().filter_map(parse_record).collect(),
+        }
+    }
+
+    pub fn save(&self, sd: &SpiSD<'_>) -> Result<(), Error<SdCardError>> {
+        let mut text = String::new();
+        for r in &self.records {
+            let last = r.last_launched.as_ref().map(rtc::format);
+            let _ = writeln!(
+                text,
+                "{}\t{}\t{}\t{}\t{}",
+                r.pinned as u8,
+                r.launches,
+                r.volume,
+                last.as_deref().unwrap_or("-"),
+                r.name
+            );
+        }
+        sd.write_state(HISTORY_FILE, text.as_bytes())
+    }
+
+    pub fn record_launch(&mut self, volume: usize, name: &str) {
+        let mut record = match self.position(volume, name) {
+            Some(i) => self.records.remove(i),
+            None => LaunchRecord {
+                volume,
+                name: name.into(),
+                launches: 0,
+                last_launched: None,
+                pinned: false,
+            },
+        };
+        record.launches += 1;
+        record.last_launched = rtc::now();
+        self.records.insert(0, record);
+
+        let mut unpinned = 0;
+        self.records.retain(|r| {
+            unpinned += !r.pinned as usize;
+            r.pinned || unpinned <= MAX_RECENTS
+        });
+    }
+
+    /// Pins or unpins an entry, returning whether it is now pinned.
+    pub fn toggle_pin(&mut self, volume: usize, name: &str) -> bool {
+        match self.position(volume, name) {
+            Some(i) => {
+                let record = &mut self.records[i];
+                record.pinned = !record.pinned;
+                record.pinned
+            }
+            None => {
+                self.records.push(LaunchRecord {
+                    volume,
+                    name: name.into(),
+                    launches: 0,
+                    last_launched: None,
+                    pinned: true,
+                });
+                true
+            }
+        }
+    }
+
+    pub fn recents(&self) -> impl Iterator<Item = &LaunchRecord> {
+        self.records.iter().filter(|r| r.launches > 0)
+    }
+
+    pub fn favourites(&self) -> impl Iterator<Item = &LaunchRecord> {
+        self.records.iter().filter(|r| r.pinned)
+    }
+
+    fn position(&self, volume: usize, name: &str) -> Option<usize> {
+        self.records
+            .iter()
+            .position(|r| r.volume == volume && r.name == name)
+    }
+}
+
+// pinned, launches, volume, last launched, name, separated by tabs
+fn parse_record(line: &str) -> Option<LaunchRecord> {
+    let mut fields = line.splitn(5, '\t');
+    let pinned = fields.next()?.trim() == "1";
+    let launches = fields.next()?.trim().parse().ok()?;
+    let volume = fields.next()?.trim().parse().ok()?;
+    let last_launched = rtc::parse(fields.next()?);
+    let name = fields.next()?.trim_end_matches('\r');
+    if name.is_empty() {
+        return None;
+    }
+    Some(LaunchRecord {
+        volume,
+        name: name.into(),
+        launches,
+        last_launched,
+        pinned,
+    })
+}
@@ -0,0 +1,152 @@
+use defmt::*;
+use embassy_rp::uart::{BufferedUart, Config as UartConfig};
+use embassy_time::{Duration, with_timeout};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+use static_cell::StaticCell;
+
+use crate::ui::controller::ButtonEvent;
+use crate::{HostResources, Irqs, rtc, send_button};
+
+const LINE_LENGTH: usize = 64;
+
+// A terminal sends the rest of an escape sequence straight after the ESC, so
+// an ESC followed by this long a pause was pressed on its own.
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(50);
+
+static TX_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+static RX_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+
+// Typed characters drive the menu like a keyboard. A line starting with ':'
+// is a command instead, e.g. `:time 2025-06-01 12:00:00`.
+enum State {
+    Keys,
+    Command,
+    Escape,
+    Csi,
+    CsiTail,
+}
+
+fn run_command(line: &str) -> &'static str {
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+    match command {
+        "time" => match rtc::parse(args) {
+            Some(dt) => {
+                rtc::set(dt);
+                "ok"
+            }
+            None => "error: expected YYYY-MM-DD HH:MM:SS",
+        },
+        "" => "",
+        _ => "error: unknown command",
+    }
+}
+
+fn key_event(c: u8) -> Option<ButtonEvent> {
+    match c {
+        b'\r' | b'\n' => Some(ButtonEvent::Select),
+        0x08 | 0x7f => Some(ButtonEvent::Backspace),
+        // Ctrl-U
+        0x15 => Some(ButtonEvent::ClearSearch),
+        c if c.is_ascii_graphic() || c == b' ' => Some(ButtonEvent::Key(c as char)),
+        _ => None,
+    }
+}
+
+// Command and keyboard interface on UART0, for a host connected over serial.
+#[embassy_executor::task]
+pub async fn host_task(r: HostResources) {
+    let tx_buf = TX_BUF.init([0; 64]);
+    let rx_buf = RX_BUF.init([0; 64]);
+    let mut uart = BufferedUart::new(
+        r.uart,
+        r.tx,
+        r.rx,
+        Irqs,
+        tx_buf,
+        rx_buf,
+        UartConfig::default(),
+    );
+
+    let mut state = State::Keys;
+    let mut line: String<LINE_LENGTH> = String::new();
+    let mut buf = [0u8; 1];
+    loop {
+        let read = uart.read_exact(&mut buf);
+        let result = if matches!(state, State::Escape) {
+            match with_timeout(ESCAPE_TIMEOUT, read).await {
+                Ok(result) => result,
+                Err(_) => {
+                    send_button(ButtonEvent::ClearSearch);
+                    state = State::Keys;
+                    continue;
+                }
+            }
+        } else {
+            read.await
+        };
+        if result.is_err() {
+            continue;
+        }
+        let c = buf[0];
+        state = match state {
+            State::Keys => match c {
+                b':' => State::Command,
+                0x1b => State::Escape,
+                c => {
+                    if let Some(event) = key_event(c) {
+                        send_button(event);
+                    }
+                    State::Keys
+                }
+            },
+            State::Command => match c {
+                b'\r' | b'\n' => {
+                    let reply = run_command(line.trim());
+                    info!("host: {} -> {}", line.as_str(), reply);
+                    if !reply.is_empty() {
+                        let _ = uart.write_all(reply.as_bytes()).await;
+                        let _ = uart.write_all(b"\r\n").await;
+                    }
+                    line.clear();
+                    State::Keys
+                }
+                c if c.is_ascii() && !c.is_ascii_control() => {
+                    if line.push(c as char).is_err() {
+                        line.clear();
+                    }
+                    State::Command
+                }
+                _ => State::Command,
+            },
+            // Arrow keys arrive as ESC [ A / ESC [ B; a lone ESC clears the
+            // search, and is seen by the pause after it or the next key
+            State::Escape => match c {
+                b'[' => State::Csi,
+                c => {
+                    send_button(ButtonEvent::ClearSearch);
+                    if let Some(event) = key_event(c) {
+                        send_button(event);
+                    }
+                    State::Keys
+                }
+            },
+            State::Csi => {
+                match c {
+                    b'A' => send_button(ButtonEvent::Up),
+                    b'B' => send_button(ButtonEvent::Down),
+                    b'5' => send_button(ButtonEvent::PageUp),
+                    b'6' => send_button(ButtonEvent::PageDown),
+                    _ => {}
+                }
+                // Page Up/Down end in '~'
+                if c == b'5' || c == b'6' {
+                    State::CsiTail
+                } else {
+                    State::Keys
+                }
+            }
+            State::CsiTail => State::Keys,
+        };
+    }
+}
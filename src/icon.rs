@@ -0,0 +1,170 @@
+use slint::{Image, Rgb8Pixel, Rgba8Pixel, SharedPixelBuffer};
+
+// Icons are decoded onto a small heap, so anything bigger than this is
+// refused rather than scaled. Slint scales them up for the grid.
+pub const MAX_SIZE: u32 = 32;
+// The biggest file a MAX_SIZE icon can be: a 32 bits per pixel BMP with
+// its header, or a QOI of nothing but RGBA literals
+pub const MAX_FILE_SIZE: usize = {
+    let pixels = (MAX_SIZE * MAX_SIZE) as usize;
+    let bmp = 138 + pixels * 4;
+    let qoi = 14 + pixels * 5 + 8;
+    if bmp > qoi { bmp } else { qoi }
+};
+
+/// Decodes a BMP (24 or 32 bits per pixel, uncompressed) or QOI icon.
+/// Opaque icons are kept without their alpha channel, to save memory.
+pub fn decode(data: &[u8]) -> Option<Image> {
+    let buffer = match data.get(..4)? {
+        [b'B', b'M', ..] => decode_bmp(data)?,
+        b"qoif" => decode_qoi(data)?,
+        _ => return None,
+    };
+    if buffer.as_slice().iter().any(|px| px.a != 0xff) {
+        return Some(Image::from_rgba8(buffer));
+    }
+    let mut opaque = SharedPixelBuffer::<Rgb8Pixel>::new(buffer.width(), buffer.height());
+    for (to, from) in opaque.make_mut_slice().iter_mut().zip(buffer.as_slice()) {
+        *to = Rgb8Pixel {
+            r: from.r,
+            g: from.g,
+            b: from.b,
+        };
+    }
+    Some(Image::from_rgb8(opaque))
+}
+
+/// At most how much heap a decoded icon takes.
+pub fn footprint(image: &Image) -> usize {
+    let size = image.size();
+    (size.width * size.height) as usize * 4
+}
+
+fn decode_bmp(data: &[u8]) -> Option<SharedPixelBuffer<Rgba8Pixel>> {
+    let u16_at = |offset: usize| {
+        Some(u16::from_le_bytes(
+            data.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let u32_at = |offset: usize| {
+        Some(u32::from_le_bytes(
+            data.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let pixels = u32_at(10)? as usize;
+    let width = u32_at(18)? as i32;
+    let height = u32_at(22)? as i32;
+    let bpp = u16_at(28)? as usize;
+    let compression = u32_at(30)?;
+    // Bitfields are only accepted in the usual BGRA order
+    if !matches!((bpp, compression), (24, 0) | (32, 0) | (32, 3)) {
+        return None;
+    }
+    // The masks follow the info header, or are part of a V4 or V5 one
+    let masks = (u32_at(54), u32_at(58), u32_at(62));
+    if compression == 3 && masks != (Some(0x00ff_0000), Some(0x0000_ff00), Some(0x0000_00ff)) {
+        return None;
+    }
+    // Rows are stored bottom-up unless the height is negative
+    let (w, h) = (width.unsigned_abs(), height.unsigned_abs());
+    if w == 0 || h == 0 || w > MAX_SIZE || h > MAX_SIZE {
+        return None;
+    }
+    let stride = (bpp * w as usize).div_ceil(32) * 4;
+    let bytes = bpp / 8;
+    // The fourth byte of a plain 32 bpp pixel is reserved and usually 0, so
+    // it's only alpha when a V4 or V5 header has a mask for it
+    let has_alpha = compression == 3 && u32_at(14)? >= 56 && u32_at(66) == Some(0xff00_0000);
+    let mut buffer = SharedPixelBuffer::<Rgba8Pixel>::new(w, h);
+    let out = buffer.make_mut_slice();
+    for y in 0..h as usize {
+        let row = if height > 0 { h as usize - 1 - y } else { y };
+        let start = pixels + row * stride;
+        let src = data.get(start..start + w as usize * bytes)?;
+        for (x, px) in src.chunks_exact(bytes).enumerate() {
+            out[y * w as usize + x] = Rgba8Pixel {
+                r: px[2],
+                g: px[1],
+                b: px[0],
+                a: if has_alpha { px[3] } else { 0xff },
+            };
+        }
+    }
+    Some(buffer)
+}
+
+fn decode_qoi(data: &[u8]) -> Option<SharedPixelBuffer<Rgba8Pixel>> {
+    let u32_at = |offset: usize| {
+        Some(u32::from_be_bytes(
+            data.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let (w, h) = (u32_at(4)?, u32_at(8)?);
+    if w == 0 || h == 0 || w > MAX_SIZE || h > MAX_SIZE {
+        return None;
+    }
+    let mut buffer = SharedPixelBuffer::<Rgba8Pixel>::new(w, h);
+    let out = buffer.make_mut_slice();
+    let mut index = [Rgba8Pixel {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 0,
+    }; 64];
+    let mut px = Rgba8Pixel {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 0xff,
+    };
+    let mut pos = 14;
+    let mut run = 0;
+    for pixel in out.iter_mut() {
+        if run > 0 {
+            run -= 1;
+        } else {
+            let op = *data.get(pos)?;
+            pos += 1;
+            match op {
+                0xfe => {
+                    let [r, g, b] = data.get(pos..pos + 3)?.try_into().ok()?;
+                    px = Rgba8Pixel { r, g, b, a: px.a };
+                    pos += 3;
+                }
+                0xff => {
+                    let [r, g, b, a] = data.get(pos..pos + 4)?.try_into().ok()?;
+                    px = Rgba8Pixel { r, g, b, a };
+                    pos += 4;
+                }
+                _ => match op >> 6 {
+                    0 => px = index[op as usize],
+                    1 => {
+                        px.r = px.r.wrapping_add((op >> 4) & 3).wrapping_sub(2);
+                        px.g = px.g.wrapping_add((op >> 2) & 3).wrapping_sub(2);
+                        px.b = px.b.wrapping_add(op & 3).wrapping_sub(2);
+                    }
+                    2 => {
+                        let next = *data.get(pos)?;
+                        pos += 1;
+                        let dg = (op & 0x3f).wrapping_sub(32);
+                        px.r =
+                            px.r.wrapping_add(dg)
+                                .wrapping_add(next >> 4)
+                                .wrapping_sub(8);
+                        px.g = px.g.wrapping_add(dg);
+                        px.b =
+                            px.b.wrapping_add(dg)
+                                .wrapping_add(next & 0xf)
+                                .wrapping_sub(8);
+                    }
+                    _ => run = op & 0x3f,
+                },
+            }
+            let hash =
+                px.r as usize * 3 + px.g as usize * 5 + px.b as usize * 7 + px.a as usize * 11;
+            index[hash % 64] = px;
+        }
+        *pixel = px;
+    }
+    Some(buffer)
+}
@@ -0,0 +1,33 @@
+use core::ops::Range;
+
+// Symbols defined in memory.x. Only their addresses mean anything.
+unsafe extern "C" {
+    static __config_start: u8;
+    static __config_end: u8;
+    static __store_start: u8;
+    static __store_end: u8;
+    static __menu_start: u8;
+    static __menu_end: u8;
+}
+
+/// The CONFIG block, which holds the panel's setup.
+pub fn config() -> Range<u32> {
+    (&raw const __config_start) as u32..(&raw const __config_end) as u32
+}
+
+/// The settings kept by store.rs.
+pub fn store() -> Range<u32> {
+    (&raw const __store_start) as u32..(&raw const __store_end) as u32
+}
+
+/// The menu itself, just above the store.
+pub fn menu() -> Range<u32> {
+    (&raw const __menu_start) as u32..(&raw const __menu_end) as u32
+}
+
+/// Whether writing `range` would overwrite the menu, its store or CONFIG.
+pub fn is_protected(range: &Range<u32>) -> bool {
+    [config(), store(), menu()]
+        .iter()
+        .any(|protected| range.start < protected.end && protected.start < range.end)
+}
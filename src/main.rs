@@ -4,18 +4,22 @@
 use alloc::boxed::Box;
 use assign_resources::assign_resources;
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, Either3, select, select3};
 use embassy_rp::Peri;
+use embassy_rp::bind_interrupts;
 use embassy_rp::gpio::Input;
 use embassy_rp::gpio::Pull;
 use embassy_rp::peripherals;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
 use embassy_sync::signal::Signal;
 use embassy_time::Duration;
 use embassy_time::Timer;
-use slint::platform::software_renderer::MinimalSoftwareWindow;
+use embassy_time::with_timeout;
 use slint::platform::software_renderer::RepaintBufferType;
 use static_cell::StaticCell;
 
+use defmt::warn;
 use defmt_rtt as _;
 use panic_probe as _;
 
@@ -23,22 +27,32 @@ use config::CONFIG_ILI9341;
 use rp2040_boot2::BOOT_LOADER_W25Q080_TOP64K;
 
 use crate::display::Display;
-use crate::display::FRAME_SIZE;
 use crate::sd::SpiSD;
-use crate::ui::backend::PicoBackend;
+use crate::ui::backend::{PicoBackend, PicoWindow};
 use crate::ui::controller::ButtonEvent;
 
 use core::ptr::addr_of_mut;
+use portable_atomic::{AtomicU32, Ordering};
 
 extern crate alloc;
 
 use embedded_alloc::LlffHeap as Heap;
 
+mod binary_info;
 mod boot;
+mod collection;
 mod config;
 mod display;
 mod flash;
+mod history;
+mod host;
+mod icon;
+mod layout;
+mod rtc;
 mod sd;
+mod settings;
+mod sidecar;
+mod store;
 mod uf2;
 mod ui;
 
@@ -66,6 +80,7 @@ assign_resources! {
         dc: PIN_20,
         rst: PIN_21,
         backlight: PIN_22,
+        pwm: PWM_SLICE3,
         dma: DMA_CH0,
     },
     sd: SdResources {
@@ -83,13 +98,51 @@ assign_resources! {
         select_pin: PIN_4,
         refresh_pin: PIN_5,
     },
+    card_detect: CardDetectResources {
+        cd_pin: PIN_14,
+    },
+    rtc: RtcResources {
+        rtc: RTC,
+    },
+    i2c: I2cResources {
+        i2c: I2C0,
+        sda: PIN_8,
+        scl: PIN_9,
+    },
+    host: HostResources {
+        uart: UART0,
+        tx: PIN_0,
+        rx: PIN_1,
+    },
 }
 
+bind_interrupts!(pub struct Irqs {
+    I2C0_IRQ => embassy_rp::i2c::InterruptHandler<peripherals::I2C0>;
+    UART0_IRQ => embassy_rp::uart::BufferedInterruptHandler<peripherals::UART0>;
+});
+
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
-static HEAP_SIZE: usize = (FRAME_SIZE * 2) + 32768;
-
-static BUTTON_SIGNAL: Signal<ThreadModeRawMutex, ButtonEvent> = Signal::new();
+#[cfg(not(feature = "line-buffer"))]
+static HEAP_SIZE: usize = (display::MAX_FRAME_SIZE * 2) + 32768;
+// Without the framebuffer: the same 32 KB for the UI, plus 16 KB so the
+// icon cache (12 KB), an icon file and image details never run short. The
+// rest of the framebuffer's RAM is left free.
+#[cfg(feature = "line-buffer")]
+static HEAP_SIZE: usize = display::LINE_BUFFERS_SIZE + 32768 + 16384;
+
+static BUTTON_CHANNEL: Channel<ThreadModeRawMutex, ButtonEvent, 8> = Channel::new();
+const LONG_PRESS: Duration = Duration::from_millis(800);
+
+// Up/Down repeat timings in milliseconds, from the settings file
+static REPEAT_DELAY: AtomicU32 = AtomicU32::new(800);
+static REPEAT_RATE: AtomicU32 = AtomicU32::new(300);
+const CHORD_WINDOW: Duration = Duration::from_millis(60);
+
+static CARD_SIGNAL: Signal<ThreadModeRawMutex, bool> = Signal::new();
+// How long time.txt waits for the RTC chip to be read, in case the I2C bus
+// is stuck
+const RTC_WAIT: Duration = Duration::from_secs(1);
 
 static TFT: StaticCell<Display<'_>> = StaticCell::new();
 static SD: StaticCell<SpiSD<'_>> = StaticCell::new();
@@ -107,16 +160,23 @@ async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
     let r = split_resources!(p);
 
-    let window = MinimalSoftwareWindow::new(RepaintBufferType::ReusedBuffer);
-    window.set_size(slint::PhysicalSize::new(
-        display::WIDTH as u32,
-        display::HEIGHT as u32,
-    ));
-    let backend = Box::new(PicoBackend::new(window.clone()));
-    slint::platform::set_platform(backend).expect("backend already initialized");
+    rtc::init(r.rtc.rtc);
+    spawner.spawn(rtc::rtc_task(r.i2c)).expect("rtc task");
+    spawner.spawn(host::host_task(r.host)).expect("host task");
+
+    // The panel's size and rotation can be changed in the store, so it's
+    // read before the display is set up
+    let store = store::Store::new();
+    let geometry = display::Geometry::load(&store);
 
-    let display: &mut Display<'_> = TFT.init(display::Display::new(r.display).await);
+    let display: &mut Display<'_> = TFT.init(display::Display::new(r.display, geometry).await);
     display.backlight(true).await;
+    let (width, height) = display.size();
+
+    let window = PicoWindow::new(RepaintBufferType::ReusedBuffer);
+    window.set_size(slint::PhysicalSize::new(width as u32, height as u32));
+    let backend = Box::new(PicoBackend::new(window.clone()));
+    slint::platform::set_platform(backend).expect("backend already initialized");
 
     spawner
         .spawn(render_loop(window, display))
@@ -125,74 +185,262 @@ async fn main(spawner: Spawner) {
     let ui: &'static FileSelector = UI.init(FileSelector::new().expect("fileselector"));
     ui.show().expect("unable to show main window");
 
-    let sd: &'static SpiSD<'_> = match sd::SpiSD::new(r.sd) {
-        Err(e) => panic!("failed to read card: {:?}", e),
-        Ok(sd) => SD.init(sd),
-    };
+    let sd: &'static SpiSD<'_> = SD.init(sd::SpiSD::new(r.sd));
 
-    let controller = CONTROLLER.init(Controller::new(ui, sd).expect("controller"));
+    let controller = CONTROLLER
+        .init(Controller::new(ui, sd, store, geometry, (width, height)).expect("controller"));
 
-    spawner.spawn(ui_task(controller)).expect("ui_task");
+    spawner.spawn(ui_task(controller, sd)).expect("ui_task");
     spawner
         .spawn(button_handler(r.buttons))
         .expect("button task");
+    spawner
+        .spawn(card_detect(sd, r.card_detect))
+        .expect("card detect task");
+}
+
+// Sets the clock from `time.txt` on the card, unless an RTC chip or an
+// earlier run has already set it. The chip is read first, so that its time
+// wins.
+async fn load_time_file(sd: &SpiSD<'_>) {
+    if with_timeout(RTC_WAIT, rtc::chip_read()).await.is_err() {
+        warn!("time.txt: gave up waiting for the RTC chip");
+    }
+    if rtc::is_set() {
+        return;
+    }
+    let mut buf = [0u8; 32];
+    if let Ok(len) = sd.read_file("/TIME.TXT", &mut buf) {
+        match core::str::from_utf8(&buf[..len]).ok().and_then(rtc::parse) {
+            Some(dt) => rtc::set(dt),
+            None => warn!("time.txt: expected YYYY-MM-DD HH:MM:SS"),
+        }
+    }
 }
 
 #[embassy_executor::task]
-async fn ui_task(controller: &'static Controller<'static>) {
-    // Initial file load
-    controller.refresh_files().await;
+async fn ui_task(controller: &'static Controller<'static>, sd: &'static SpiSD<'static>) {
+    controller.update_clock();
+    // Only autoboot on the first card seen after power-on
+    let mut first_card = true;
 
     loop {
-        // Wait for button event
-        let button_event = BUTTON_SIGNAL.wait().await;
-
-        match button_event {
-            ButtonEvent::Refresh => {
-                controller.refresh_files().await;
+        // Wait for button or card events, updating the clock while idle
+        let button_event = match select3(
+            BUTTON_CHANNEL.receive(),
+            CARD_SIGNAL.wait(),
+            Timer::after_secs(1),
+        )
+        .await
+        {
+            Either3::First(button_event) => button_event,
+            Either3::Second(true) => {
+                let problems = controller.card_inserted().await;
+                load_time_file(sd).await;
+                // Booting straight past the problems would hide them, and
+                // the autoboot setting itself may be one of them
+                if core::mem::take(&mut first_card) && !problems {
+                    autoboot(controller).await;
+                }
+                continue;
             }
-            ButtonEvent::Select => {
-                controller.handle_button(button_event);
+            Either3::Second(false) => {
+                controller.card_removed();
+                continue;
             }
-            _ => {
-                controller.handle_button(button_event);
+            Either3::Third(_) => {
+                controller.update_clock();
+                continue;
             }
-        }
+        };
+
+        controller.handle_button(button_event).await;
 
         Timer::after(Duration::from_millis(10)).await;
     }
 }
 
+// Counts down to launching the configured file, unless a button is
+// pressed first.
+async fn autoboot(controller: &Controller<'_>) {
+    let Some((volume, path, delay)) = controller.autoboot_target() else {
+        return;
+    };
+    for remaining in (1..=delay).rev() {
+        controller.show_autoboot(&path, remaining);
+        if let Either::First(_) = select(BUTTON_CHANNEL.receive(), Timer::after_secs(1)).await {
+            controller.cancel_autoboot();
+            return;
+        }
+    }
+    controller.launch(volume, &path);
+}
+
 // Button handler task
 #[embassy_executor::task]
 async fn button_handler(r: ButtonResources) {
-    let up_button = Input::new(r.up_pin, Pull::Up);
-    let down_button = Input::new(r.down_pin, Pull::Up);
-    let select_button = Input::new(r.select_pin, Pull::Up);
-    let refresh_button = Input::new(r.refresh_pin, Pull::Up);
+    let mut up_button = Input::new(r.up_pin, Pull::Up);
+    let mut down_button = Input::new(r.down_pin, Pull::Up);
+    let mut select_button = Input::new(r.select_pin, Pull::Up);
+    let mut refresh_button = Input::new(r.refresh_pin, Pull::Up);
 
     loop {
-        // Check each button (active low with pull-up)
-        if up_button.is_low() {
-            BUTTON_SIGNAL.signal(ButtonEvent::Up);
-            Timer::after(Duration::from_millis(200)).await; // Debounce
+        // Check each button (active low with pull-up), latching what's down
+        // before waiting for a chord so that a quick tap isn't lost
+        let mut up = up_button.is_low();
+        let mut down = down_button.is_low();
+        let mut select = select_button.is_low();
+        let mut refresh = refresh_button.is_low();
+        if !(up || down || select || refresh) {
+            Timer::after(Duration::from_millis(50)).await;
+            continue;
         }
 
-        if down_button.is_low() {
-            BUTTON_SIGNAL.signal(ButtonEvent::Down);
-            Timer::after(Duration::from_millis(200)).await;
+        // Give the other buttons a moment to join a chord, whichever came
+        // first
+        Timer::after(CHORD_WINDOW).await;
+        up |= up_button.is_low();
+        down |= down_button.is_low();
+        select |= select_button.is_low();
+        refresh |= refresh_button.is_low();
+
+        match (up, down, select, refresh) {
+            (true, true, _, _) => {
+                send_button(ButtonEvent::NextLetter);
+                up_button.wait_for_high().await;
+                down_button.wait_for_high().await;
+            }
+            (true, _, true, _) => {
+                // Up and select together switch between list and grid
+                send_button(ButtonEvent::ToggleView);
+                up_button.wait_for_high().await;
+                select_button.wait_for_high().await;
+            }
+            (_, true, true, _) => {
+                // Down and select together go back
+                send_button(ButtonEvent::Back);
+                down_button.wait_for_high().await;
+                select_button.wait_for_high().await;
+            }
+            (_, _, true, true) => {
+                // Select and refresh together open the details screen
+                send_button(ButtonEvent::Details);
+                select_button.wait_for_high().await;
+                refresh_button.wait_for_high().await;
+                Timer::after(Duration::from_millis(200)).await;
+            }
+            (true, _, _, _) => {
+                repeat_press(&mut up_button, ButtonEvent::Up, ButtonEvent::PageUp).await;
+            }
+            (_, true, _, _) => {
+                repeat_press(&mut down_button, ButtonEvent::Down, ButtonEvent::PageDown).await;
+            }
+            (_, _, true, _) => {
+                // Holding select pins the entry instead of launching it. Down
+                // arriving late still makes Back, as Select would confirm a
+                // flash.
+                match select3(
+                    select_button.wait_for_high(),
+                    down_button.wait_for_low(),
+                    Timer::after(LONG_PRESS),
+                )
+                .await
+                {
+                    Either3::First(_) => send_button(ButtonEvent::Select),
+                    Either3::Second(_) => {
+                        send_button(ButtonEvent::Back);
+                        down_button.wait_for_high().await;
+                        select_button.wait_for_high().await;
+                    }
+                    Either3::Third(_) => {
+                        send_button(ButtonEvent::Pin);
+                        select_button.wait_for_high().await;
+                    }
+                }
+                Timer::after(Duration::from_millis(200)).await;
+            }
+            (_, _, _, true) => {
+                // Holding refresh switches views instead
+                let held = with_timeout(LONG_PRESS, refresh_button.wait_for_high())
+                    .await
+                    .is_err();
+                if held {
+                    send_button(ButtonEvent::NextView);
+                    refresh_button.wait_for_high().await;
+                } else {
+                    send_button(ButtonEvent::Refresh);
+                }
+                Timer::after(Duration::from_millis(200)).await;
+            }
+            (false, false, false, false) => {}
         }
 
-        if select_button.is_low() {
-            BUTTON_SIGNAL.signal(ButtonEvent::Select);
-            Timer::after(Duration::from_millis(200)).await;
-        }
+        Timer::after(Duration::from_millis(50)).await; // Debounce
+    }
+}
 
-        if refresh_button.is_low() {
-            BUTTON_SIGNAL.signal(ButtonEvent::Refresh);
-            Timer::after(Duration::from_millis(200)).await;
-        }
+// A short press moves once; holding the button pages through the list.
+async fn repeat_press(button: &mut Input<'_>, press: ButtonEvent, held: ButtonEvent) {
+    send_button(press);
+    let delay = Duration::from_millis(REPEAT_DELAY.load(Ordering::Relaxed) as u64);
+    if with_timeout(delay, button.wait_for_high()).await.is_ok() {
+        return;
+    }
+    let rate = Duration::from_millis(REPEAT_RATE.load(Ordering::Relaxed) as u64);
+    while button.is_low() {
+        send_button(held);
+        let _ = with_timeout(rate, button.wait_for_high()).await;
+    }
+}
+
+pub fn set_repeat_timing(delay_ms: u32, rate_ms: u32) {
+    REPEAT_DELAY.store(delay_ms, Ordering::Relaxed);
+    REPEAT_RATE.store(rate_ms, Ordering::Relaxed);
+}
+
+pub fn send_button(event: ButtonEvent) {
+    // Drop presses rather than block if the UI has fallen behind
+    let _ = BUTTON_CHANNEL.try_send(event);
+}
+
+// Watches for the card coming and going, using the card-detect switch if
+// the board has one wired up, or by probing the card otherwise.
+#[embassy_executor::task]
+async fn card_detect(sd: &'static SpiSD<'static>, r: CardDetectResources) {
+    #[cfg(feature = "card-detect")]
+    let mut cd_pin = Input::new(r.cd_pin, Pull::Up);
+    #[cfg(not(feature = "card-detect"))]
+    let _ = r;
 
-        Timer::after(Duration::from_millis(50)).await;
+    let mut present = sd.probe();
+    CARD_SIGNAL.signal(present);
+
+    loop {
+        #[cfg(feature = "card-detect")]
+        let now_present = {
+            cd_pin.wait_for_any_edge().await;
+            Timer::after(Duration::from_millis(100)).await; // Debounce
+
+            // The switch pulls the pin low while a card is seated
+            if cd_pin.is_low() {
+                sd.probe()
+            } else {
+                if sd.is_present() {
+                    sd.invalidate();
+                }
+                false
+            }
+        };
+
+        #[cfg(not(feature = "card-detect"))]
+        let now_present = {
+            Timer::after(Duration::from_millis(1000)).await;
+            sd.probe()
+        };
+
+        if now_present != present {
+            present = now_present;
+            CARD_SIGNAL.signal(present);
+        }
     }
 }
@@ -0,0 +1,302 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use defmt::*;
+use embassy_rp::{
+    Peri,
+    i2c::{Async, Config as I2cConfig, I2c},
+    peripherals::{I2C0, RTC},
+    rtc::{DateTime, DayOfWeek, Rtc},
+};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::ThreadModeRawMutex},
+    once_lock::OnceLock,
+    signal::Signal,
+};
+use embedded_hal_async::i2c::I2c as _;
+use heapless::String;
+
+use crate::{I2cResources, Irqs};
+
+type RtcI2c = I2c<'static, I2C0, Async>;
+
+const DS3231_ADDR: u8 = 0x68;
+const PCF8563_ADDR: u8 = 0x51;
+
+// DS3231 status register, whose top bit says the oscillator has stopped
+const DS3231_STATUS: u8 = 0x0f;
+const DS3231_OSF: u8 = 0x80;
+
+static RTC_CELL: Mutex<ThreadModeRawMutex, RefCell<Option<Rtc<'static, RTC>>>> =
+    Mutex::new(RefCell::new(None));
+
+// Times set from the card or the host, to be written back to the external chip.
+static SYNC_SIGNAL: Signal<ThreadModeRawMutex, DateTime> = Signal::new();
+
+// Set once the external chip has been probed and, if fitted, read.
+static CHIP_READ: OnceLock<()> = OnceLock::new();
+
+#[derive(Clone, Copy, PartialEq)]
+enum Chip {
+    Ds3231,
+    Pcf8563,
+}
+
+pub fn init(rtc: Peri<'static, RTC>) {
+    let rtc = Rtc::new(rtc);
+    RTC_CELL.lock(|cell| cell.replace(Some(rtc)));
+}
+
+/// The current time, or `None` if nothing has set the clock yet.
+pub fn now() -> Option<DateTime> {
+    RTC_CELL.lock(|cell| cell.borrow().as_ref().and_then(|rtc| rtc.now().ok()))
+}
+
+pub fn is_set() -> bool {
+    now().is_some()
+}
+
+/// Waits until the external chip's time, if there is one, has been taken,
+/// so that a time from elsewhere isn't then overwritten by it.
+pub async fn chip_read() {
+    CHIP_READ.get().await;
+}
+
+/// Sets the RP2040 RTC, and the external RTC chip if there is one.
+pub fn set(dt: DateTime) {
+    set_internal(dt.clone());
+    SYNC_SIGNAL.signal(dt);
+}
+
+fn set_internal(dt: DateTime) {
+    RTC_CELL.lock(|cell| {
+        if let Some(rtc) = cell.borrow_mut().as_mut() {
+            if rtc.set_datetime(dt).is_err() {
+                warn!("rtc: invalid datetime");
+            }
+        }
+    });
+}
+
+/// Parses `YYYY-MM-DD HH:MM[:SS]`, as found in `time.txt` or sent by the host.
+pub fn parse(s: &str) -> Option<DateTime> {
+    let s = s.trim();
+    let (date, time) = s.split_once([' ', 'T'])?;
+
+    let mut date = date.split('-').map(|p| p.parse::<u16>().ok());
+    let year = date.next()??;
+    let month = date.next()?? as u8;
+    let day = date.next()?? as u8;
+
+    let mut time = time.trim().split(':').map(|p| p.parse::<u8>().ok());
+    let hour = time.next()??;
+    let minute = time.next()??;
+    let second = time.next().unwrap_or(Some(0))?;
+
+    if !(1970..2100).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    Some(DateTime {
+        year,
+        month,
+        day,
+        day_of_week: day_of_week(year, month, day),
+        hour,
+        minute,
+        second,
+    })
+}
+
+/// Formats a time as `YYYY-MM-DD HH:MM:SS`, the form `parse` accepts.
+pub fn format(dt: &DateTime) -> String<19> {
+    let mut s = String::new();
+    let _ = write!(
+        s,
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+    );
+    s
+}
+
+// Sakamoto's method
+fn day_of_week(year: u16, month: u8, day: u8) -> DayOfWeek {
+    const T: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+    let y = if month < 3 { year - 1 } else { year };
+    let dow = (y + y / 4 - y / 100 + y / 400 + T[month as usize - 1] + day as u16) % 7;
+    dow_from_u8(dow as u8)
+}
+
+fn dow_from_u8(dow: u8) -> DayOfWeek {
+    match dow {
+        0 => DayOfWeek::Sunday,
+        1 => DayOfWeek::Monday,
+        2 => DayOfWeek::Tuesday,
+        3 => DayOfWeek::Wednesday,
+        4 => DayOfWeek::Thursday,
+        5 => DayOfWeek::Friday,
+        _ => DayOfWeek::Saturday,
+    }
+}
+
+fn bcd(v: u8) -> u8 {
+    (v >> 4) * 10 + (v & 0x0f)
+}
+
+fn to_bcd(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
+}
+
+async fn probe(i2c: &mut RtcI2c) -> Option<Chip> {
+    let mut buf = [0u8; 1];
+    if i2c.write_read(DS3231_ADDR, &[0x00], &mut buf).await.is_ok() {
+        return Some(Chip::Ds3231);
+    }
+    if i2c
+        .write_read(PCF8563_ADDR, &[0x02], &mut buf)
+        .await
+        .is_ok()
+    {
+        return Some(Chip::Pcf8563);
+    }
+    None
+}
+
+async fn read_chip(i2c: &mut RtcI2c, chip: Chip) -> Option<DateTime> {
+    let mut r = [0u8; 7];
+    let (second, minute, hour, day, month, year) = match chip {
+        Chip::Ds3231 => {
+            // OSF: the oscillator stopped, so the time is not to be trusted
+            let mut status = [0u8; 1];
+            i2c.write_read(DS3231_ADDR, &[DS3231_STATUS], &mut status)
+                .await
+                .ok()?;
+            if status[0] & DS3231_OSF != 0 {
+                return None;
+            }
+            i2c.write_read(DS3231_ADDR, &[0x00], &mut r).await.ok()?;
+            (
+                r[0] & 0x7f,
+                r[1] & 0x7f,
+                r[2] & 0x3f,
+                r[4] & 0x3f,
+                r[5] & 0x1f,
+                r[6],
+            )
+        }
+        Chip::Pcf8563 => {
+            i2c.write_read(PCF8563_ADDR, &[0x02], &mut r).await.ok()?;
+            // VL bit: the oscillator stopped, so the time is not to be trusted
+            if r[0] & 0x80 != 0 {
+                return None;
+            }
+            (
+                r[0] & 0x7f,
+                r[1] & 0x7f,
+                r[2] & 0x3f,
+                r[3] & 0x3f,
+                r[5] & 0x1f,
+                r[6],
+            )
+        }
+    };
+    let (year, month, day) = (2000 + bcd(year) as u16, bcd(month), bcd(day));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(DateTime {
+        year,
+        month,
+        day,
+        day_of_week: day_of_week(year, month, day),
+        hour: bcd(hour),
+        minute: bcd(minute),
+        second: bcd(second),
+    })
+}
+
+async fn write_chip(i2c: &mut RtcI2c, chip: Chip, dt: &DateTime) {
+    let year = to_bcd((dt.year % 100) as u8);
+    let dow = dt.day_of_week as u8;
+    let result = match chip {
+        Chip::Ds3231 => {
+            let regs = [
+                0x00,
+                to_bcd(dt.second),
+                to_bcd(dt.minute),
+                to_bcd(dt.hour),
+                dow + 1,
+                to_bcd(dt.day),
+                to_bcd(dt.month),
+                year,
+            ];
+            match i2c.write(DS3231_ADDR, &regs).await {
+                // The time is good again, so clear OSF
+                Ok(()) => clear_osf(i2c).await,
+                Err(e) => Err(e),
+            }
+        }
+        Chip::Pcf8563 => {
+            let regs = [
+                0x02,
+                to_bcd(dt.second),
+                to_bcd(dt.minute),
+                to_bcd(dt.hour),
+                to_bcd(dt.day),
+                dow,
+                to_bcd(dt.month),
+                year,
+            ];
+            i2c.write(PCF8563_ADDR, &regs).await
+        }
+    };
+    if result.is_err() {
+        warn!("rtc: failed to write external chip");
+    }
+}
+
+async fn clear_osf(i2c: &mut RtcI2c) -> Result<(), embassy_rp::i2c::Error> {
+    let mut status = [0u8; 1];
+    i2c.write_read(DS3231_ADDR, &[DS3231_STATUS], &mut status)
+        .await?;
+    i2c.write(DS3231_ADDR, &[DS3231_STATUS, status[0] & !DS3231_OSF])
+        .await
+}
+
+// Loads the time from an external DS3231 or PCF8563, if one is fitted, and
+// keeps it in step with times set from elsewhere.
+#[embassy_executor::task]
+pub async fn rtc_task(r: I2cResources) {
+    let mut i2c = I2c::new_async(r.i2c, r.scl, r.sda, Irqs, I2cConfig::default());
+
+    let chip = probe(&mut i2c).await;
+    match chip {
+        Some(Chip::Ds3231) => info!("rtc: found DS3231"),
+        Some(Chip::Pcf8563) => info!("rtc: found PCF8563"),
+        None => info!("rtc: no external chip"),
+    }
+
+    if let Some(chip) = chip {
+        match read_chip(&mut i2c, chip).await {
+            // A time set by the host while probing is newer, and is about
+            // to be written to the chip
+            Some(dt) if !SYNC_SIGNAL.signaled() => set_internal(dt),
+            Some(_) => {}
+            None => warn!("rtc: external chip has no valid time"),
+        }
+    }
+    let _ = CHIP_READ.init(());
+
+    loop {
+        let dt = SYNC_SIGNAL.wait().await;
+        if let Some(chip) = chip {
+            write_chip(&mut i2c, chip, &dt).await;
+        }
+    }
+}
@@ -1,48 +1,109 @@
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+
 use defmt::*;
-use embassy_time::Delay;
+use embassy_time::{Delay, Instant};
 use embedded_hal_02::spi::MODE_0;
 use embedded_hal_bus::spi::ExclusiveDevice;
 use embedded_sdmmc::{
-    DirEntry, Error, File, LfnBuffer, SdCard, SdCardError, TimeSource, Timestamp, VolumeIdx,
-    VolumeManager,
+    Block, BlockDevice, BlockIdx, DirEntry, Directory, Error, File, LfnBuffer, Mode, SdCard,
+    SdCardError, ShortFileName, TimeSource, Timestamp, VolumeIdx, VolumeManager,
+    sdcard::AcquireOpts,
 };
 
+use alloc::vec::Vec;
 use embassy_rp::{
     gpio::{Level, Output},
     peripherals::SPI1,
     spi::{Async, Config as SpiConfig, Spi},
 };
-use slint::{SharedString, ToSharedString, VecModel};
+use heapless::String;
+use slint::{SharedString, ToSharedString};
 
-use crate::SdResources;
+use crate::{SdResources, rtc};
 
-// This is just a placeholder TimeSource. In a real world application
-// one would probably use the RTC to provide time.
+// Timestamps come from the RP2040 RTC, once something has set it.
 pub struct Clock;
 
 impl TimeSource for Clock {
     fn get_timestamp(&self) -> Timestamp {
-        Timestamp {
-            year_since_1970: 0,
-            zero_indexed_month: 0,
-            zero_indexed_day: 0,
-            hours: 0,
-            minutes: 0,
-            seconds: 0,
+        match rtc::now() {
+            Some(now) => Timestamp {
+                year_since_1970: now.year.saturating_sub(1970) as u8,
+                zero_indexed_month: now.month - 1,
+                zero_indexed_day: now.day - 1,
+                hours: now.hour,
+                minutes: now.minute,
+                seconds: now.second,
+            },
+            None => Timestamp {
+                year_since_1970: 0,
+                zero_indexed_month: 0,
+                zero_indexed_day: 0,
+                hours: 0,
+                minutes: 0,
+                seconds: 0,
+            },
         }
     }
 }
 
+// The spec requires cards to be initialised at 100-400 kHz.
+const INIT_CLOCK: u32 = 400_000;
+
+// Clocks to try once the card is up, fastest first. 25 MHz is the limit for
+// default-speed cards.
+const CLOCKS: [u32; 7] = [
+    25_000_000, 20_000_000, 15_625_000, 12_500_000, 8_000_000, 4_000_000, 1_000_000,
+];
+
+// Number of times a test block must read back identically at a given clock.
+const CLOCK_TEST_READS: usize = 4;
+
+// MBR partitions, each of which may hold a FAT volume
+const MAX_VOLUMES: usize = 4;
+
+// Folder on the first partition where the menu keeps its own state
+const STATE_DIR: &str = "MENU";
+const VOLUME_STATE: &str = "VOLUME.TXT";
+
+// Blocks read by the throughput benchmark, in batches of BENCH_BATCH.
+const BENCH_BLOCKS: u32 = 256;
+const BENCH_BATCH: usize = 8;
+
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    pub card_size: u64,
+    pub clock_hz: u32,
+    pub fallbacks: u32,
+    pub read_bytes_per_sec: u32,
+}
+
+pub struct Partition {
+    pub index: usize,
+    pub label: SharedString,
+}
+
 pub type SdSpiDevice<'spi> = ExclusiveDevice<Spi<'spi, SPI1, Async>, Output<'spi>, Delay>;
+type Card<'spi> = SdCard<SdSpiDevice<'spi>, Delay>;
+pub type SdFile<'a, 'spi> = File<'a, Card<'spi>, Clock, 4, 4, 1>;
+type SdDir<'a, 'spi> = Directory<'a, Card<'spi>, Clock, 4, 4, 1>;
 
 pub struct SpiSD<'spi> {
-    volume_manager: VolumeManager<SdCard<SdSpiDevice<'spi>, Delay>, Clock>,
+    volume_manager: VolumeManager<Card<'spi>, Clock>,
+    present: Cell<bool>,
+    generation: Cell<u32>,
+    clock: Cell<Option<usize>>,
+    stats: Cell<Stats>,
+    volume: Cell<usize>,
+    // The current directory, as short names to open and the names shown
+    dir: RefCell<Vec<(ShortFileName, alloc::string::String)>>,
 }
 
 impl<'spi> SpiSD<'spi> {
-    pub fn new(res: SdResources) -> Result<SpiSD<'spi>, Error<SdCardError>> {
+    pub fn new(res: SdResources) -> SpiSD<'spi> {
         let mut spi_cfg = SpiConfig::default();
-        spi_cfg.frequency = 12_000_000;
+        spi_cfg.frequency = INIT_CLOCK;
         spi_cfg.polarity = MODE_0.polarity;
         spi_cfg.phase = MODE_0.phase;
 
@@ -56,62 +117,470 @@ impl<'spi> SpiSD<'spi> {
             .expect("failed to create exclusive bus for sd");
 
         let timer = embassy_time::Delay;
-        let sdcard = SdCard::new(spi_device, timer);
-
-        let card_size = match sdcard.num_bytes() {
-            Ok(size) => size,
-            Err(e) => return Err(Error::DeviceError(e)),
-        };
+        // The card isn't touched until the first probe. CRC checking lets
+        // clock negotiation spot marginal wiring.
+        let sdcard = SdCard::new_with_options(
+            spi_device,
+            timer,
+            AcquireOpts {
+                use_crc: true,
+                ..Default::default()
+            },
+        );
 
-        info!("Card size is {} bytes", card_size);
         let timesource = Clock {};
         let volume_manager = VolumeManager::new(sdcard, timesource);
-        Ok(SpiSD { volume_manager })
+        SpiSD {
+            volume_manager,
+            present: Cell::new(false),
+            generation: Cell::new(0),
+            clock: Cell::new(None),
+            stats: Cell::new(Stats::default()),
+            volume: Cell::new(0),
+            dir: RefCell::new(Vec::new()),
+        }
     }
 
-    pub fn list_files(&self) -> VecModel<SharedString> {
-        let files = VecModel::<SharedString>::default();
-        self.iterate_root_dir(|entry, lfn| {
-            if let Some(name) = lfn {
-                files.push(SharedString::from(name));
-            } else {
-                files.push(entry.name.to_shared_string());
+    /// Checks for a card, initialising it if it has just been inserted.
+    /// Returns whether a card is present.
+    pub fn probe(&self) -> bool {
+        let result = self.volume_manager.device(|card| {
+            let result = card.num_bytes();
+            if result.is_err() {
+                card.mark_card_uninit();
+            }
+            result
+        });
+        match result {
+            Ok(card_size) => {
+                if !self.present.get() {
+                    info!("Card size is {} bytes", card_size);
+                    self.present.set(true);
+                    self.generation.set(self.generation.get().wrapping_add(1));
+                    self.stats.set(Stats {
+                        card_size,
+                        ..Stats::default()
+                    });
+                    self.negotiate_clock();
+                }
+                true
+            }
+            Err(_) => {
+                if self.present.get() {
+                    self.invalidate();
+                }
+                false
+            }
+        }
+    }
+
+    /// Forgets the current card. Anything derived from it is stale once the
+    /// generation has moved on.
+    pub fn invalidate(&self) {
+        info!("Card removed");
+        self.volume_manager.device(|card| card.mark_card_uninit());
+        self.set_clock(INIT_CLOCK);
+        self.clock.set(None);
+        self.present.set(false);
+        self.generation.set(self.generation.get().wrapping_add(1));
+    }
+
+    fn set_clock(&self, hz: u32) {
+        self.volume_manager
+            .device(|card| card.spi(|dev| dev.bus_mut().set_frequency(hz)));
+        let mut stats = self.stats.get();
+        stats.clock_hz = hz;
+        self.stats.set(stats);
+    }
+
+    fn read_raw(&self, blocks: &mut [Block], start: u32) -> Result<(), SdCardError> {
+        self.volume_manager
+            .device(|card| card.read(blocks, BlockIdx(start)))
+    }
+
+    // Picks the fastest clock at which a test block reads back the same as
+    // it did at the init clock.
+    fn negotiate_clock(&self) {
+        let mut reference = [Block::new()];
+        if self.read_raw(&mut reference, 0).is_err() {
+            warn!("SD: test read failed at {} Hz", INIT_CLOCK);
+            return;
+        }
+        for (i, &hz) in CLOCKS.iter().enumerate() {
+            self.set_clock(hz);
+            let reliable = (0..CLOCK_TEST_READS).all(|_| {
+                let mut test = [Block::new()];
+                self.read_raw(&mut test, 0).is_ok() && test[0].contents == reference[0].contents
+            });
+            if reliable {
+                info!("SD: clock {} Hz", hz);
+                self.clock.set(Some(i));
+                return;
+            }
+        }
+        warn!("SD: no reliable clock, staying at {} Hz", INIT_CLOCK);
+        self.set_clock(INIT_CLOCK);
+    }
+
+    /// Drops to the next slower clock after a device error. Returns whether
+    /// the failed operation is worth retrying.
+    pub fn recover(&self, e: &Error<SdCardError>) -> bool {
+        if !matches!(e, Error::DeviceError(_)) || !self.present.get() {
+            return false;
+        }
+        match self.clock.get() {
+            Some(i) if i + 1 < CLOCKS.len() => {
+                warn!("SD: {}, slowing to {} Hz", e, CLOCKS[i + 1]);
+                self.clock.set(Some(i + 1));
+                self.set_clock(CLOCKS[i + 1]);
+                let mut stats = self.stats.get();
+                stats.fallbacks += 1;
+                self.stats.set(stats);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn retrying<T>(
+        &self,
+        mut func: impl FnMut() -> Result<T, Error<SdCardError>>,
+    ) -> Result<T, Error<SdCardError>> {
+        loop {
+            match func() {
+                Err(e) if self.recover(&e) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Times raw block reads from the start of the card.
+    pub fn benchmark(&self) -> Result<Stats, SdCardError> {
+        let mut blocks = [const { Block::new() }; BENCH_BATCH];
+        let start = Instant::now();
+        for idx in (0..BENCH_BLOCKS).step_by(BENCH_BATCH) {
+            self.read_raw(&mut blocks, idx)?;
+        }
+        let elapsed_us = start.elapsed().as_micros().max(1);
+        let bytes = BENCH_BLOCKS as u64 * Block::LEN as u64;
+
+        let mut stats = self.stats.get();
+        stats.read_bytes_per_sec = (bytes * 1_000_000 / elapsed_us) as u32;
+        self.stats.set(stats);
+        Ok(stats)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.get()
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present.get()
+    }
+
+    /// Changes whenever a card is inserted or removed.
+    pub fn generation(&self) -> u32 {
+        self.generation.get()
+    }
+
+    /// The FAT volumes on the card, with their labels.
+    pub fn partitions(&self) -> Vec<Partition> {
+        (0..MAX_VOLUMES)
+            .filter_map(|index| {
+                let label = self.volume_label(index).ok()?;
+                Some(Partition { index, label })
+            })
+            .collect()
+    }
+
+    pub fn volume(&self) -> usize {
+        self.volume.get()
+    }
+
+    fn volume_label(&self, index: usize) -> Result<SharedString, Error<SdCardError>> {
+        let volume = self.volume_manager.open_volume(VolumeIdx(index))?;
+        let root_dir = volume.open_root_dir()?;
+        let mut label: String<11> = String::new();
+        root_dir.iterate_dir(|entry| {
+            if entry.attributes.is_volume() && label.is_empty() {
+                for &b in entry.name.base_name().iter().chain(entry.name.extension()) {
+                    let _ = label.push(b as char);
+                }
+            }
+        })?;
+        Ok(label.trim_end().into())
+    }
+
+    /// Switches to another volume and remembers the choice on the card.
+    /// Returns the volume label.
+    pub fn select_volume(&self, index: usize) -> Result<SharedString, Error<SdCardError>> {
+        let label = self.volume_label(index)?;
+        self.volume.set(index);
+        self.dir.borrow_mut().clear();
+        let mut buf: String<4> = String::new();
+        let _ = write!(buf, "{}", index);
+        if let Err(e) = self.write_state(VOLUME_STATE, buf.as_bytes()) {
+            warn!("SD: failed to save volume choice: {}", e);
+        }
+        Ok(label)
+    }
+
+    /// Goes back to the volume chosen last time, if the card still has it.
+    /// Returns the volume label.
+    pub fn restore_volume(&self) -> SharedString {
+        self.dir.borrow_mut().clear();
+        let mut buf = [0u8; 4];
+        let saved = self
+            .read_state(VOLUME_STATE, &mut buf)
+            .ok()
+            .and_then(|len| core::str::from_utf8(&buf[..len]).ok()?.trim().parse().ok())
+            .filter(|&index| index < MAX_VOLUMES);
+        for index in saved.into_iter().chain(0..MAX_VOLUMES) {
+            if let Ok(label) = self.volume_label(index) {
+                self.volume.set(index);
+                return label;
+            }
+        }
+        self.volume.set(0);
+        SharedString::new()
+    }
+
+    /// Reads up to `buf.len()` bytes of a file in the menu's state folder.
+    pub fn read_state(&self, filename: &str, buf: &mut [u8]) -> Result<usize, Error<SdCardError>> {
+        self.retrying(|| {
+            let volume = self.volume_manager.open_volume(VolumeIdx(0))?;
+            let root_dir = volume.open_root_dir()?;
+            let state_dir = root_dir.open_dir(STATE_DIR)?;
+            let f = state_dir.open_file_in_dir(filename, Mode::ReadOnly)?;
+            read_to_end(&f, buf)
+        })
+    }
+
+    /// Replaces a file in the menu's state folder, creating the folder if
+    /// needed.
+    pub fn write_state(&self, filename: &str, data: &[u8]) -> Result<(), Error<SdCardError>> {
+        self.retrying(|| {
+            let volume = self.volume_manager.open_volume(VolumeIdx(0))?;
+            let root_dir = volume.open_root_dir()?;
+            match root_dir.make_dir_in_dir(STATE_DIR) {
+                Ok(()) | Err(Error::DirAlreadyExists) => {}
+                Err(e) => return Err(e),
+            }
+            let state_dir = root_dir.open_dir(STATE_DIR)?;
+            let f = state_dir.open_file_in_dir(filename, Mode::ReadWriteCreateOrTruncate)?;
+            f.write(data)?;
+            f.close()
+        })
+    }
+
+    /// Enters a subdirectory of the current directory, or leaves it if
+    /// `name` is `..`.
+    pub fn enter_dir(&self, name: &str) -> Result<(), Error<SdCardError>> {
+        let name = name.trim_end_matches('/');
+        if name == ".." {
+            self.dir.borrow_mut().pop();
+            return Ok(());
+        }
+        let short_name = self.with_dir(|dir| find_entry(dir, name))?;
+        self.dir.borrow_mut().push((short_name, name.into()));
+        Ok(())
+    }
+
+    /// Changes to a directory given as a path from the root of the volume.
+    pub fn set_dir(&self, path: &str) -> Result<(), Error<SdCardError>> {
+        let old = self.dir.replace(Vec::new());
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if let Err(e) = self.enter_dir(component) {
+                self.dir.replace(old);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// The current directory's path from the root of the volume, with a
+    /// trailing `/`.
+    pub fn dir_path(&self) -> alloc::string::String {
+        let mut path = alloc::string::String::from("/");
+        for (_, name) in self.dir.borrow().iter() {
+            let _ = write!(path, "{}/", name);
+        }
+        path
+    }
+
+    fn with_dir<T>(
+        &self,
+        func: impl FnOnce(&SdDir<'_, 'spi>) -> Result<T, Error<SdCardError>>,
+    ) -> Result<T, Error<SdCardError>> {
+        let volume = self
+            .volume_manager
+            .open_volume(VolumeIdx(self.volume.get()))?;
+        let mut dir = volume.open_root_dir()?;
+        for (short_name, _) in self.dir.borrow().iter() {
+            dir = dir.open_dir(short_name.clone())?;
+        }
+        func(&dir)
+    }
+
+    // Opens the directory holding `path`, which is relative to the current
+    // directory unless it starts with '/', and finds the short name of the
+    // last component.
+    fn with_parent<T>(
+        &self,
+        path: &str,
+        func: impl FnOnce(&SdDir<'_, 'spi>, ShortFileName) -> Result<T, Error<SdCardError>>,
+    ) -> Result<T, Error<SdCardError>> {
+        let volume = self
+            .volume_manager
+            .open_volume(VolumeIdx(self.volume.get()))?;
+        let mut dir = volume.open_root_dir()?;
+        let path = match path.strip_prefix('/') {
+            Some(path) => path,
+            None => {
+                for (short_name, _) in self.dir.borrow().iter() {
+                    dir = dir.open_dir(short_name.clone())?;
+                }
+                path
+            }
+        };
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+        while let Some(component) = components.next() {
+            let short_name = find_entry(&dir, component)?;
+            if components.peek().is_none() {
+                return func(&dir, short_name);
+            }
+            dir = dir.open_dir(short_name)?;
+        }
+        Err(Error::NotFound)
+    }
+
+    /// Reads the names of up to `max` entries, starting from the
+    /// `start`th listed entry, along with how many entries there are.
+    /// Directories end in `/`. embedded-sdmmc can neither seek within a
+    /// directory nor stop part way, so this walks all of it regardless.
+    pub fn read_files(
+        &self,
+        start: usize,
+        max: usize,
+    ) -> Result<(Vec<SharedString>, usize), Error<SdCardError>> {
+        self.retrying(|| {
+            let mut files = Vec::new();
+            let mut index = 0;
+            self.iterate_dir(|entry, lfn| {
+                if !is_listed(entry) {
+                    return;
+                }
+                if index >= start && files.len() < max {
+                    files.push(entry_name(entry, lfn));
+                }
+                index += 1;
+            })?;
+            Ok((files, index))
+        })
+    }
+
+    /// Calls `func` with the index and name of every listed entry.
+    pub fn iterate_files(
+        &self,
+        mut func: impl FnMut(usize, &str),
+    ) -> Result<(), Error<SdCardError>> {
+        let mut index = 0;
+        self.iterate_dir(|entry, lfn| {
+            if is_listed(entry) {
+                func(index, &entry_name(entry, lfn));
+                index += 1;
             }
         })
-        .expect("iterate root dir");
-        files
     }
 
-    pub fn iterate_root_dir(
+    /// Iterates over the current directory.
+    pub fn iterate_dir(
         &self,
         mut func: impl FnMut(&DirEntry, Option<&str>),
     ) -> Result<(), Error<SdCardError>> {
-        let volume0 = self.volume_manager.open_volume(VolumeIdx(0))?;
-        info!("Volume 0: {:?}", volume0);
-        let root_dir = volume0.open_root_dir()?;
-        let mut binding = [0u8; 256];
-        let mut lfn_buffer = LfnBuffer::new(&mut binding);
-        root_dir.iterate_dir_lfn(&mut lfn_buffer, |entry: &DirEntry, lfn: Option<&str>| {
-            info!("Entry: {} {}", defmt::Display2Format(&entry.name), lfn);
-            func(entry, lfn);
-        })?;
-        Ok(())
+        self.with_dir(|dir| {
+            let mut binding = [0u8; 256];
+            let mut lfn_buffer = LfnBuffer::new(&mut binding);
+            dir.iterate_dir_lfn(&mut lfn_buffer, |entry: &DirEntry, lfn: Option<&str>| {
+                trace!("Entry: {} {}", defmt::Display2Format(&entry.name), lfn);
+                func(entry, lfn);
+            })
+        })
     }
 
+    /// Opens a file by its long or short name. Paths are relative to the
+    /// current directory unless they start with '/'.
     pub fn open(
         &self,
-        filename: &str,
-        func: impl FnOnce(&File<'_, SdCard<SdSpiDevice<'_>, Delay>, Clock, 4, 4, 1>),
+        path: &str,
+        func: impl FnOnce(&SdFile<'_, 'spi>),
     ) -> Result<(), Error<SdCardError>> {
-        let volume0 = self
-            .volume_manager
-            .open_volume(VolumeIdx(0))
-            .expect("failed to open volume");
-        let root_dir = volume0.open_root_dir().expect("failed to open root dir");
-        let f = root_dir
-            .open_file_in_dir(filename, embedded_sdmmc::Mode::ReadOnly)
-            .expect("failed to open file");
-        func(&f);
-        Ok(())
+        self.with_parent(path, |dir, short_name| {
+            let f = dir.open_file_in_dir(short_name, Mode::ReadOnly)?;
+            func(&f);
+            Ok(())
+        })
+    }
+
+    /// The size of a file in bytes.
+    pub fn file_size(&self, path: &str) -> Result<u32, Error<SdCardError>> {
+        self.retrying(|| {
+            self.with_parent(path, |dir, short_name| {
+                Ok(dir.find_directory_entry(short_name)?.size)
+            })
+        })
+    }
+
+    /// Reads up to `buf.len()` bytes of a file.
+    pub fn read_file(&self, path: &str, buf: &mut [u8]) -> Result<usize, Error<SdCardError>> {
+        self.retrying(|| {
+            self.with_parent(path, |dir, short_name| {
+                let f = dir.open_file_in_dir(short_name, Mode::ReadOnly)?;
+                read_to_end(&f, buf)
+            })
+        })
+    }
+}
+
+fn is_listed(entry: &DirEntry) -> bool {
+    !entry.attributes.is_volume() && entry.name != ShortFileName::this_dir()
+}
+
+fn entry_name(entry: &DirEntry, lfn: Option<&str>) -> SharedString {
+    let mut name = match lfn {
+        Some(name) => SharedString::from(name),
+        None => entry.name.to_shared_string(),
+    };
+    if entry.attributes.is_directory() {
+        name.push_str("/");
+    }
+    name
+}
+
+// Finds the short name of an entry given either its long or short name.
+fn find_entry(dir: &SdDir<'_, '_>, name: &str) -> Result<ShortFileName, Error<SdCardError>> {
+    let mut found = None;
+    let mut binding = [0u8; 256];
+    let mut lfn_buffer = LfnBuffer::new(&mut binding);
+    dir.iterate_dir_lfn(&mut lfn_buffer, |entry, lfn| {
+        if found.is_some() {
+            return;
+        }
+        let mut short_name: String<12> = String::new();
+        let _ = write!(short_name, "{}", entry.name);
+        if lfn.is_some_and(|lfn| lfn.eq_ignore_ascii_case(name))
+            || short_name.eq_ignore_ascii_case(name)
+        {
+            found = Some(entry.name.clone());
+        }
+    })?;
+    found.ok_or(Error::NotFound)
+}
+
+fn read_to_end(f: &SdFile<'_, '_>, buf: &mut [u8]) -> Result<usize, Error<SdCardError>> {
+    let mut len = 0;
+    while len < buf.len() && !f.is_eof() {
+        len += f.read(&mut buf[len..])?;
     }
+    Ok(len)
 }
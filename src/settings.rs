@@ -0,0 +1,289 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use defmt::*;
+use embedded_sdmmc::Error;
+
+use crate::sd::SpiSD;
+
+const SETTINGS_FILE: &str = "SETTINGS.INI";
+const SETTINGS_SIZE: usize = 4096;
+
+pub const THEMES: &[&str] = &["dark", "light", "high-contrast"];
+// Languages with translations under lang/, besides English
+pub const LANGUAGES: &[&str] = &["en", "de"];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    // As stored on the card
+    Directory,
+    Name,
+    NameDescending,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    List,
+    // Thumbnails and short titles
+    Grid,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum Autoboot {
+    Off,
+    // Whatever was launched most recently
+    Last,
+    File(String),
+}
+
+/// Menu settings, read from `MENU/SETTINGS.INI` on the card, e.g.
+///
+/// ```ini
+/// [list]
+/// start_dir = /games
+/// sort = name
+/// view = list
+///
+/// [boot]
+/// autoboot = last
+/// autoboot_delay = 3
+/// confirm = on
+///
+/// [display]
+/// brightness = 80
+/// theme = dark
+/// large_text = off
+/// smooth_scroll = off
+/// language = en
+/// width = 320
+/// height = 240
+/// rotation = 0
+///
+/// A panel bigger than 320x240, such as a 480x320 one, is only used whole by
+/// a `line-buffer` build; otherwise the UI fills a band across its middle.
+///
+/// [buttons]
+/// repeat_delay = 800
+/// repeat_rate = 300
+/// ```
+#[derive(Clone)]
+pub struct Settings {
+    pub start_dir: String,
+    pub sort: SortOrder,
+    // For directories without a view of their own
+    pub view: View,
+    pub autoboot: Autoboot,
+    // Seconds to wait for a button press before autobooting
+    pub autoboot_delay: u32,
+    // Ask before flashing, which kiosk setups may not want
+    pub confirm_flash: bool,
+    // Backlight level, 0-100
+    pub brightness: u8,
+    pub theme: String,
+    pub large_text: bool,
+    pub smooth_scroll: bool,
+    pub language: String,
+    // The panel's size and mounting, which take a restart to change. A full
+    // framebuffer for more than 320x240 doesn't fit in RAM, so bigger panels
+    // only get a band across the middle unless built with `line-buffer`.
+    pub display_width: u16,
+    pub display_height: u16,
+    pub rotation: u16,
+    // Milliseconds before a held button starts repeating, and between repeats
+    pub repeat_delay: u32,
+    pub repeat_rate: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            start_dir: "/".into(),
+            sort: SortOrder::Directory,
+            view: View::List,
+            autoboot: Autoboot::Off,
+            autoboot_delay: 3,
+            confirm_flash: true,
+            brightness: 100,
+            theme: "dark".into(),
+            large_text: false,
+            smooth_scroll: false,
+            language: "en".into(),
+            display_width: 320,
+            display_height: 240,
+            rotation: 0,
+            repeat_delay: 800,
+            repeat_rate: 300,
+        }
+    }
+}
+
+/// Something wrong with the settings file, described to the user in their
+/// language by the controller.
+pub enum Problem {
+    // The file couldn't be read, with the error
+    Unreadable(String),
+    NotUtf8,
+    // A problem with the numbered line
+    Line(usize, LineProblem),
+}
+
+pub enum LineProblem {
+    UnclosedSection,
+    NotKeyValue,
+    // The value should have been one of `choices`, e.g. "on, off"
+    Choice { key: String, choices: &'static str },
+    Range { key: String, min: u32, max: u32 },
+    UnknownTheme(String),
+    UnknownLanguage(String),
+    // With its section, if it had one, e.g. "list.sort"
+    UnknownKey(String),
+}
+
+impl Settings {
+    /// Reads the settings file, falling back to `base` for anything missing
+    /// or invalid. Returns each problem found, to show to the user.
+    pub fn load(sd: &SpiSD<'_>, base: Settings) -> (Settings, Vec<Problem>) {
+        let mut buf = vec![0u8; SETTINGS_SIZE];
+        let len = match sd.read_state(SETTINGS_FILE, &mut buf) {
+            Ok(len) => len,
+            Err(Error::NotFound) => 0,
+            Err(e) => {
+                warn!("settings: failed to read: {}", e);
+                return (base, vec![Problem::Unreadable(alloc::format!("{:?}", e))]);
+            }
+        };
+        match core::str::from_utf8(&buf[..len]) {
+            Ok(text) => parse(text, base),
+            Err(_) => (base, vec![Problem::NotUtf8]),
+        }
+    }
+}
+
+pub fn parse(text: &str, base: Settings) -> (Settings, Vec<Problem>) {
+    let mut settings = base;
+    let mut problems = Vec::new();
+    let mut section = "";
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let mut problem = |problem| problems.push(Problem::Line(index + 1, problem));
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            match name.strip_suffix(']') {
+                Some(name) => section = name.trim(),
+                None => problem(LineProblem::UnclosedSection),
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            problem(LineProblem::NotKeyValue);
+            continue;
+        };
+        let (key, value) = (key.trim(), unquote(value.trim()));
+        if let Err(e) = settings.set(section, key, value) {
+            problem(e);
+        }
+    }
+    (settings, problems)
+}
+
+impl Settings {
+    fn set(&mut self, section: &str, key: &str, value: &str) -> Result<(), LineProblem> {
+        match (section, key) {
+            ("list", "start_dir") => self.start_dir = value.into(),
+            ("list", "sort") => {
+                self.sort = match value {
+                    "dir" | "none" => SortOrder::Directory,
+                    "name" => SortOrder::Name,
+                    "name-desc" => SortOrder::NameDescending,
+                    _ => return Err(choice("sort", "dir, name, name-desc")),
+                }
+            }
+            ("list", "view") => {
+                self.view = match value {
+                    "list" => View::List,
+                    "grid" => View::Grid,
+                    _ => return Err(choice("view", "list, grid")),
+                }
+            }
+            ("boot", "autoboot") => {
+                self.autoboot = match value {
+                    "off" | "" => Autoboot::Off,
+                    "last" => Autoboot::Last,
+                    file => Autoboot::File(file.into()),
+                }
+            }
+            ("boot", "autoboot_delay") => self.autoboot_delay = number(key, value, 0, 60)?,
+            ("boot", "confirm") => self.confirm_flash = boolean(key, value)?,
+            ("display", "brightness") => self.brightness = number(key, value, 0, 100)? as u8,
+            ("display", "theme") => {
+                if !THEMES.contains(&value) {
+                    return Err(LineProblem::UnknownTheme(value.into()));
+                }
+                self.theme = value.into();
+            }
+            ("display", "large_text") => self.large_text = boolean(key, value)?,
+            ("display", "smooth_scroll") => self.smooth_scroll = boolean(key, value)?,
+            ("display", "language") => {
+                if !LANGUAGES.contains(&value) {
+                    return Err(LineProblem::UnknownLanguage(value.into()));
+                }
+                self.language = value.into();
+            }
+            ("display", "width") => self.display_width = number(key, value, 64, 1024)? as u16,
+            ("display", "height") => self.display_height = number(key, value, 64, 1024)? as u16,
+            ("display", "rotation") => {
+                self.rotation = match value {
+                    "0" => 0,
+                    "90" => 90,
+                    "180" => 180,
+                    "270" => 270,
+                    _ => return Err(choice("rotation", "0, 90, 180, 270")),
+                }
+            }
+            ("buttons", "repeat_delay") => self.repeat_delay = number(key, value, 100, 5000)?,
+            ("buttons", "repeat_rate") => self.repeat_rate = number(key, value, 20, 5000)?,
+            ("", key) => return Err(LineProblem::UnknownKey(key.into())),
+            (section, key) => {
+                return Err(LineProblem::UnknownKey(alloc::format!(
+                    "{}.{}", section, key
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn choice(key: &str, choices: &'static str) -> LineProblem {
+    LineProblem::Choice {
+        key: key.into(),
+        choices,
+    }
+}
+
+fn number(key: &str, value: &str, min: u32, max: u32) -> Result<u32, LineProblem> {
+    match value.parse() {
+        Ok(n) if (min..=max).contains(&n) => Ok(n),
+        _ => Err(LineProblem::Range {
+            key: key.into(),
+            min,
+            max,
+        }),
+    }
+}
+
+fn boolean(key: &str, value: &str) -> Result<bool, LineProblem> {
+    match value {
+        "on" | "yes" | "true" | "1" => Ok(true),
+        "off" | "no" | "false" | "0" => Ok(false),
+        _ => Err(choice(key, "on, off")),
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
@@ -0,0 +1,327 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use defmt::*;
+use slint::Image;
+
+use crate::icon;
+use crate::sd::SpiSD;
+
+// Descriptors bigger than this are ignored
+const MAX_DESCRIPTOR: usize = 2048;
+const MAX_SIDECARS: usize = 128;
+// Icons waiting to be read
+const MAX_ICONS: usize = 16;
+// Heap for decoded icons, most recently used last
+const MAX_ICON_BYTES: usize = 12 * 1024;
+
+/// What `foo.toml` or `foo.json` says about `foo.uf2`, e.g.
+///
+/// ```toml
+/// title = "Space Invaders"
+/// description = "The arcade classic"
+/// author = "Someone"
+/// version = "1.2"
+/// category = "Games"
+/// icon = "invaders.qoi"
+/// ```
+#[derive(Clone, Default)]
+pub struct Descriptor {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub version: Option<String>,
+    pub category: Option<String>,
+    // An icon file in the same directory, if not `foo.bmp` or `foo.qoi`
+    pub icon: Option<String>,
+}
+
+impl Descriptor {
+    fn set(&mut self, key: &str, value: String) {
+        match key {
+            "title" => self.title = Some(value),
+            "description" => self.description = Some(value),
+            "author" => self.author = Some(value),
+            "version" => self.version = Some(value),
+            "category" => self.category = Some(value),
+            "icon" => self.icon = Some(value),
+            _ => {}
+        }
+    }
+}
+
+struct Entry {
+    // The UF2 file's name without its extension, in lower case
+    stem: String,
+    descriptor: Descriptor,
+    icon_file: Option<String>,
+}
+
+/// The descriptors and icons for the UF2 files in the current directory.
+#[derive(Default)]
+pub struct Sidecars {
+    entries: Vec<Entry>,
+    icons: Vec<(usize, Option<Image>)>,
+    icon_bytes: usize,
+    // Icons asked for by the list, to be read in the background
+    queue: Vec<usize>,
+}
+
+impl Sidecars {
+    /// Looks through the current directory for descriptors and icons.
+    /// Icons are only read when they're first shown.
+    pub fn load(sd: &SpiSD<'_>) -> Sidecars {
+        let mut descriptors = Vec::new();
+        let mut icons = Vec::new();
+        let listed = sd.iterate_files(|_, name| {
+            let Some((stem, extension)) = name.rsplit_once('.') else {
+                return;
+            };
+            let stem = stem.to_lowercase();
+            match extension.to_ascii_lowercase().as_str() {
+                "toml" | "json" if descriptors.len() < MAX_SIDECARS => {
+                    descriptors.push((stem, String::from(name)))
+                }
+                "bmp" | "qoi" if icons.len() < MAX_SIDECARS => {
+                    icons.push((stem, String::from(name)))
+                }
+                _ => {}
+            }
+        });
+        if let Err(e) = listed {
+            warn!("sidecar: can't list directory: {}", e);
+            return Sidecars::default();
+        }
+
+        let mut entries: Vec<Entry> = Vec::new();
+        for (stem, filename) in descriptors {
+            let mut buf = vec![0u8; MAX_DESCRIPTOR];
+            let descriptor = match sd.read_file(&filename, &mut buf) {
+                Ok(len) if len < MAX_DESCRIPTOR => match core::str::from_utf8(&buf[..len]) {
+                    Ok(text) if filename.to_ascii_lowercase().ends_with(".json") => {
+                        parse_json(text)
+                    }
+                    Ok(text) => parse_toml(text),
+                    Err(_) => continue,
+                },
+                Ok(_) => {
+                    warn!("sidecar: {} is too big", filename.as_str());
+                    continue;
+                }
+                Err(e) => {
+                    warn!("sidecar: can't read {}: {}", filename.as_str(), e);
+                    continue;
+                }
+            };
+            entries.push(Entry {
+                stem,
+                icon_file: descriptor.icon.clone(),
+                descriptor,
+            });
+        }
+        // Icons named after the UF2 file, for those that don't say otherwise
+        for (stem, filename) in icons {
+            match entries.iter_mut().find(|e| e.stem == stem) {
+                Some(entry) => {
+                    entry.icon_file.get_or_insert(filename);
+                }
+                None if entries.len() < MAX_SIDECARS => entries.push(Entry {
+                    stem,
+                    descriptor: Descriptor::default(),
+                    icon_file: Some(filename),
+                }),
+                None => {}
+            }
+        }
+        Sidecars {
+            entries,
+            icons: Vec::new(),
+            icon_bytes: 0,
+            queue: Vec::new(),
+        }
+    }
+
+    fn find(&self, filename: &str) -> Option<usize> {
+        let (stem, extension) = filename.rsplit_once('.')?;
+        if !extension.eq_ignore_ascii_case("uf2") {
+            return None;
+        }
+        self.entries
+            .iter()
+            .position(|e| e.stem.eq_ignore_ascii_case(stem))
+    }
+
+    pub fn get(&self, filename: &str) -> Option<&Descriptor> {
+        self.find(filename)
+            .map(|index| &self.entries[index].descriptor)
+    }
+
+    pub fn category(&self, filename: &str) -> Option<&str> {
+        self.get(filename)?.category.as_deref()
+    }
+
+    /// The categories named in the directory's descriptors, in order.
+    pub fn categories(&self) -> Vec<String> {
+        let mut categories: Vec<String> = Vec::new();
+        for entry in &self.entries {
+            if let Some(category) = &entry.descriptor.category {
+                if let Err(index) = categories.binary_search(category) {
+                    categories.insert(index, category.clone());
+                }
+            }
+        }
+        categories
+    }
+
+    /// The icon for a UF2 file, read and decoded the first time it's asked
+    /// for.
+    pub fn icon(&mut self, sd: &SpiSD<'_>, filename: &str) -> Option<Image> {
+        let index = self.find(filename)?;
+        match self.cached(index) {
+            Some(image) => image,
+            None => self.read_icon(sd, index),
+        }
+    }
+
+    /// The icon for a UF2 file if it has been read already. Otherwise it's
+    /// queued for `read_queued`, so the list isn't held up by the card.
+    pub fn queued_icon(&mut self, filename: &str) -> Option<Image> {
+        let index = self.find(filename)?;
+        self.entries[index].icon_file.as_ref()?;
+        match self.cached(index) {
+            Some(image) => image,
+            None => {
+                if !self.queue.contains(&index) {
+                    if self.queue.len() >= MAX_ICONS {
+                        self.queue.remove(0);
+                    }
+                    self.queue.push(index);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn has_queued(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Reads the icon asked for most recently, returning whether there was
+    /// one to read.
+    pub fn read_queued(&mut self, sd: &SpiSD<'_>) -> bool {
+        let Some(index) = self.queue.pop() else {
+            return false;
+        };
+        if self.cached(index).is_none() {
+            self.read_icon(sd, index);
+        }
+        true
+    }
+
+    fn cached(&mut self, index: usize) -> Option<Option<Image>> {
+        let position = self.icons.iter().position(|(i, _)| *i == index)?;
+        let cached = self.icons.remove(position);
+        let image = cached.1.clone();
+        self.icons.push(cached);
+        Some(image)
+    }
+
+    fn read_icon(&mut self, sd: &SpiSD<'_>, index: usize) -> Option<Image> {
+        let image = self.entries[index].icon_file.as_ref().and_then(|file| {
+            let mut buf = vec![0u8; icon::MAX_FILE_SIZE];
+            match sd.read_file(file, &mut buf) {
+                Ok(len) => icon::decode(&buf[..len]),
+                Err(e) => {
+                    warn!("sidecar: can't read {}: {}", file.as_str(), e);
+                    None
+                }
+            }
+        });
+        let bytes = image.as_ref().map_or(0, icon::footprint);
+        while !self.icons.is_empty() && self.icon_bytes + bytes > MAX_ICON_BYTES {
+            let (_, evicted) = self.icons.remove(0);
+            self.icon_bytes -= evicted.as_ref().map_or(0, icon::footprint);
+        }
+        self.icon_bytes += bytes;
+        self.icons.push((index, image.clone()));
+        image
+    }
+}
+
+// Top-level `key = "value"` lines; tables and other types are skipped.
+fn parse_toml(text: &str) -> Descriptor {
+    let mut descriptor = Descriptor::default();
+    let mut top_level = true;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            top_level = false;
+        }
+        if !top_level || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        let quoted = value
+            .strip_prefix('"')
+            .and_then(|v| v.split_once('"'))
+            .or_else(|| value.strip_prefix('\'').and_then(|v| v.split_once('\'')));
+        if let Some((value, _)) = quoted {
+            descriptor.set(key.trim(), value.into());
+        }
+    }
+    descriptor
+}
+
+// String members of a flat JSON object. Anything else is skipped over.
+fn parse_json(text: &str) -> Descriptor {
+    let mut descriptor = Descriptor::default();
+    let mut chars = text.chars().peekable();
+    let mut key: Option<String> = None;
+    let mut depth = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth -= 1,
+            '"' => {
+                let mut s = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('u') => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                                s.push(c.unwrap_or('\u{fffd}'));
+                            }
+                            Some(c) => s.push(c),
+                            None => break,
+                        },
+                        c => s.push(c),
+                    }
+                }
+                if depth != 1 {
+                    continue;
+                }
+                match key.take() {
+                    Some(k) => descriptor.set(&k, s),
+                    None => {
+                        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                            chars.next();
+                        }
+                        if chars.peek() == Some(&':') {
+                            key = Some(s);
+                        }
+                    }
+                }
+            }
+            ',' => key = None,
+            _ => {}
+        }
+    }
+    descriptor
+}
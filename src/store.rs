@@ -0,0 +1,329 @@
+use core::marker::PhantomData;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use defmt::*;
+use rp2040_flash::flash::*;
+
+use crate::layout;
+use crate::settings::Autoboot;
+
+// A log of key/value records in the 64K reserved as STORE in memory.x,
+// between the app area and the menu. Records are appended to one sector
+// until it fills, then the live ones are copied to the next, so writes
+// are spread around all of them.
+const FLASH_BASE: u32 = 0x1000_0000;
+const SECTOR_SIZE: usize = 4096;
+const PAGE_SIZE: usize = 256;
+
+const MAGIC: [u8; 4] = *b"KVS1";
+// Magic and sequence number
+const HEADER_SIZE: usize = 8;
+// Key, length and check byte
+const RECORD_OVERHEAD: usize = 3;
+const BLANK: u8 = 0xff;
+
+/// A key for a value of type `T`.
+pub struct Key<T> {
+    id: u8,
+    _value: PhantomData<T>,
+}
+
+impl<T> Key<T> {
+    const fn new(id: u8) -> Self {
+        Key {
+            id,
+            _value: PhantomData,
+        }
+    }
+}
+
+pub const LAST_DIR: Key<String> = Key::new(1);
+pub const LAST_SELECTION: Key<String> = Key::new(2);
+pub const BRIGHTNESS: Key<u8> = Key::new(3);
+pub const AUTOBOOT: Key<Autoboot> = Key::new(4);
+pub const THEME: Key<String> = Key::new(5);
+pub const LARGE_TEXT: Key<bool> = Key::new(6);
+pub const LANGUAGE: Key<String> = Key::new(7);
+pub const DISPLAY_WIDTH: Key<u16> = Key::new(8);
+pub const DISPLAY_HEIGHT: Key<u16> = Key::new(9);
+pub const DISPLAY_ROTATION: Key<u16> = Key::new(10);
+// Directories viewed as a list or grid, one per line, each starting with
+// 'L' or 'G', most recent first
+pub const DIR_VIEWS: Key<String> = Key::new(11);
+
+pub trait Value: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl Value for u8 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [b] => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl Value for u16 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(u16::from_le_bytes)
+    }
+}
+
+impl Value for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        u8::decode(bytes).map(|b| b != 0)
+    }
+}
+
+impl Value for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        core::str::from_utf8(bytes).ok().map(String::from)
+    }
+}
+
+impl Value for Autoboot {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Autoboot::Off => out.push(0),
+            Autoboot::Last => out.push(1),
+            Autoboot::File(path) => {
+                out.push(2);
+                out.extend_from_slice(path.as_bytes());
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes.split_first()? {
+            (0, []) => Some(Autoboot::Off),
+            (1, []) => Some(Autoboot::Last),
+            (2, path) => String::decode(path).map(Autoboot::File),
+            _ => None,
+        }
+    }
+}
+
+/// Settings kept in internal flash, so they survive without a card.
+pub struct Store {
+    sector: usize,
+    sequence: u32,
+    // Where the next record goes in the current sector
+    end: usize,
+}
+
+impl Store {
+    /// Finds the most recently written sector.
+    pub fn new() -> Store {
+        let mut current: Option<(usize, u32)> = None;
+        for sector in 0..sectors() {
+            let data = sector_data(sector);
+            if data[..4] != MAGIC {
+                continue;
+            }
+            let sequence = u32::from_le_bytes(data[4..8].try_into().unwrap());
+            if current.is_none_or(|(_, s)| sequence.wrapping_sub(s) as i32 > 0) {
+                current = Some((sector, sequence));
+            }
+        }
+        match current {
+            Some((sector, sequence)) => Store {
+                sector,
+                sequence,
+                end: scan(sector_data(sector), |_, _| {}),
+            },
+            None => {
+                info!("store: empty, starting afresh");
+                let mut store = Store {
+                    sector: sectors() - 1,
+                    sequence: 0,
+                    end: HEADER_SIZE,
+                };
+                store.compact(None);
+                store
+            }
+        }
+    }
+
+    pub fn get<T: Value>(&self, key: &Key<T>) -> Option<T> {
+        let mut latest = None;
+        scan(sector_data(self.sector), |id, value| {
+            if id == key.id {
+                latest = Some(value);
+            }
+        });
+        T::decode(latest?)
+    }
+
+    /// Stores a value, unless it's the same as the one already stored.
+    pub fn set<T: Value>(&mut self, key: &Key<T>, value: &T) {
+        let mut encoded = Vec::new();
+        value.encode(&mut encoded);
+        if encoded.len() > u8::MAX as usize {
+            warn!("store: value for key {} too long", key.id);
+            return;
+        }
+        let mut latest = None;
+        scan(sector_data(self.sector), |id, value| {
+            if id == key.id {
+                latest = Some(value);
+            }
+        });
+        if latest == Some(&encoded[..]) {
+            return;
+        }
+
+        let record = record(key.id, &encoded);
+        if self.end + record.len() > SECTOR_SIZE {
+            self.compact(Some(record));
+        } else {
+            self.append(&record);
+        }
+    }
+
+    fn append(&mut self, record: &[u8]) {
+        let start = self.end;
+        let end = start + record.len();
+        let sector_addr = self.sector * SECTOR_SIZE;
+        let mut page = [BLANK; PAGE_SIZE];
+        // Reprogram every page the record touches. Bytes already written
+        // are programmed with the same values, which leaves them intact.
+        for page_start in (start / PAGE_SIZE * PAGE_SIZE..end).step_by(PAGE_SIZE) {
+            let existing = &sector_data(self.sector)[page_start..page_start + PAGE_SIZE];
+            page.copy_from_slice(existing);
+            for (offset, byte) in page.iter_mut().enumerate() {
+                let pos = page_start + offset;
+                if (start..end).contains(&pos) {
+                    *byte = record[pos - start];
+                }
+            }
+            program(sector_addr + page_start, &page);
+        }
+        self.end = end;
+    }
+
+    // Copies the latest value of every key into the next sector, followed
+    // by `record`.
+    fn compact(&mut self, new_record: Option<Vec<u8>>) {
+        let mut latest: Vec<(u8, &[u8])> = Vec::new();
+        if self.end > HEADER_SIZE {
+            scan(sector_data(self.sector), |id, value| {
+                match latest.iter_mut().find(|(k, _)| *k == id) {
+                    Some(entry) => entry.1 = value,
+                    None => latest.push((id, value)),
+                }
+            });
+        }
+
+        let mut data = vec![BLANK; SECTOR_SIZE];
+        let sequence = self.sequence.wrapping_add(1);
+        data[..4].copy_from_slice(&MAGIC);
+        data[4..8].copy_from_slice(&sequence.to_le_bytes());
+        let mut end = HEADER_SIZE;
+        let new_id = new_record.as_ref().map(|r| r[0]);
+        let live = latest
+            .into_iter()
+            .filter(|(id, _)| Some(*id) != new_id)
+            .map(|(id, value)| record(id, value));
+        for r in live.chain(new_record) {
+            data[end..end + r.len()].copy_from_slice(&r);
+            end += r.len();
+        }
+
+        let sector = (self.sector + 1) % sectors();
+        let sector_addr = sector * SECTOR_SIZE;
+        erase(sector_addr);
+        // The header goes last, so a sector is only ever found complete
+        for page_start in (PAGE_SIZE..end).step_by(PAGE_SIZE) {
+            program(
+                sector_addr + page_start,
+                &data[page_start..page_start + PAGE_SIZE],
+            );
+        }
+        program(sector_addr, &data[..PAGE_SIZE]);
+
+        self.sector = sector;
+        self.sequence = sequence;
+        self.end = end;
+    }
+}
+
+fn record(id: u8, value: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(value.len() + RECORD_OVERHEAD);
+    record.push(id);
+    record.push(value.len() as u8);
+    record.extend_from_slice(value);
+    record.push(check(id, value));
+    record
+}
+
+fn check(id: u8, value: &[u8]) -> u8 {
+    value
+        .iter()
+        .fold(id ^ value.len() as u8 ^ 0x5a, |c, &b| c.rotate_left(1) ^ b)
+}
+
+// Calls `func` with each valid record in order, returning where the next
+// one would go. A damaged record leaves the rest of the sector unusable.
+fn scan<'a>(data: &'a [u8], mut func: impl FnMut(u8, &'a [u8])) -> usize {
+    let mut pos = HEADER_SIZE;
+    while pos + RECORD_OVERHEAD <= SECTOR_SIZE && data[pos] != BLANK {
+        let id = data[pos];
+        let len = data[pos + 1] as usize;
+        let end = pos + 2 + len;
+        if end >= SECTOR_SIZE || data[end] != check(id, &data[pos + 2..end]) {
+            warn!("store: damaged record at {}", pos);
+            return SECTOR_SIZE;
+        }
+        func(id, &data[pos + 2..end]);
+        pos = end + 1;
+    }
+    pos
+}
+
+fn sectors() -> usize {
+    let store = layout::store();
+    (store.end - store.start) as usize / SECTOR_SIZE
+}
+
+fn sector_data(sector: usize) -> &'static [u8] {
+    let addr = layout::store().start as usize + sector * SECTOR_SIZE;
+    // The store is read straight from XIP flash
+    unsafe { core::slice::from_raw_parts(addr as *const u8, SECTOR_SIZE) }
+}
+
+fn erase(offset: usize) {
+    cortex_m::interrupt::free(|_| unsafe {
+        flash_range_erase(flash_offset(offset), SECTOR_SIZE as u32, true);
+    });
+}
+
+fn program(offset: usize, data: &[u8]) {
+    cortex_m::interrupt::free(|_| unsafe {
+        flash_range_program(flash_offset(offset), data, true);
+    });
+}
+
+// From the start of flash, as the flash routines want
+fn flash_offset(offset: usize) -> u32 {
+    layout::store().start - FLASH_BASE + offset as u32
+}
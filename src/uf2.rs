@@ -1,9 +1,236 @@
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use defmt::warn;
+use embedded_sdmmc::{Error, SdCardError};
 use uf2_block::Block;
 
 const UF2_BLOCK_LENGTH: usize = 512;
+const FLASH_BASE: u32 = 0x1000_0000;
+const FLASH_SECTOR_SIZE: u32 = 4096;
+// Boot2 is kept from the menu rather than the UF2, so it isn't compared
+const BOOT2_SIZE: u32 = 256;
+const FAMILY_ID_PRESENT: u32 = 0x2000;
+const RP2040_FAMILY: u32 = 0xe48b_ff56;
 
+use crate::binary_info;
+use crate::layout;
 use crate::sd::SpiSD;
 
+/// What flashing a UF2 file would involve.
+pub struct Summary {
+    pub blocks: u32,
+    // Flash sectors that would be erased and written
+    pub sectors: u32,
+    // Whether any of it differs from what's in flash now
+    pub differs: bool,
+    // Whether it would write over the menu, its store or CONFIG, so mustn't
+    // be flashed
+    pub protected: bool,
+}
+
+/// Reads through a UF2 file without flashing it.
+pub fn summarise(sd: &SpiSD<'_>, filename: &str) -> Result<Summary, Error<SdCardError>> {
+    let mut summary = Summary {
+        blocks: 0,
+        sectors: 0,
+        differs: false,
+        protected: false,
+    };
+    let mut sectors: Vec<u32> = Vec::new();
+    scan(sd, filename, |raw, block| {
+        summary.blocks += 1;
+        add_sectors(&mut sectors, block);
+        // Flash is erased a sector at a time, so a block puts its whole
+        // sector at risk. Blocks that don't make sense can't be flashed
+        // safely either.
+        summary.protected |= extent(block).is_none_or(|Range { start, end }| {
+            let start = start / FLASH_SECTOR_SIZE * FLASH_SECTOR_SIZE;
+            let end = end
+                .div_ceil(FLASH_SECTOR_SIZE)
+                .saturating_mul(FLASH_SECTOR_SIZE);
+            layout::is_protected(&(start..end))
+        });
+        if !summary.differs {
+            summary.differs = differs_from_flash(raw, block);
+        }
+    })?;
+    summary.sectors = sectors.len() as u32;
+    Ok(summary)
+}
+
+/// Everything the details screen shows about a UF2 file.
+pub struct Analysis {
+    pub blocks: u32,
+    pub families: Vec<u32>,
+    pub lowest: u32,
+    // The end of the highest block
+    pub highest: u32,
+    // Bytes of flash in the sectors written
+    pub footprint: u32,
+    pub overlaps_menu: bool,
+    pub overlaps_config: bool,
+    // Whether it has a block for boot2, which the menu replaces with its own
+    pub own_boot2: bool,
+    // CRC-32 of the payloads, in file order
+    pub crc: u32,
+}
+
+pub fn analyse(sd: &SpiSD<'_>, filename: &str) -> Result<Analysis, Error<SdCardError>> {
+    let mut analysis = Analysis {
+        blocks: 0,
+        families: Vec::new(),
+        lowest: u32::MAX,
+        highest: 0,
+        footprint: 0,
+        overlaps_menu: false,
+        overlaps_config: false,
+        own_boot2: false,
+        crc: 0,
+    };
+    let mut sectors: Vec<u32> = Vec::new();
+    let mut crc = !0;
+    scan(sd, filename, |raw, block| {
+        analysis.blocks += 1;
+        let flags = word(raw, 8);
+        if flags & FAMILY_ID_PRESENT != 0 {
+            let family = word(raw, 28);
+            if !analysis.families.contains(&family) {
+                analysis.families.push(family);
+            }
+        }
+        // Blocks that run off the end of memory or their own data are
+        // counted, but not looked into
+        let Some(Range { start, end }) = extent(block) else {
+            return;
+        };
+        analysis.lowest = analysis.lowest.min(start);
+        analysis.highest = analysis.highest.max(end);
+        add_sectors(&mut sectors, block);
+        analysis.overlaps_menu |=
+            overlaps(start..end, layout::store()) || overlaps(start..end, layout::menu());
+        analysis.overlaps_config |= overlaps(start..end, layout::config());
+        analysis.own_boot2 |= start == FLASH_BASE;
+        crc = crc32(crc, &block.data[..(end - start) as usize]);
+    })?;
+    if analysis.blocks == 0 {
+        analysis.lowest = 0;
+    }
+    analysis.footprint = sectors.len() as u32 * FLASH_SECTOR_SIZE;
+    analysis.crc = !crc;
+    Ok(analysis)
+}
+
+// Calls `func` with each block of a UF2 file, raw and parsed, stopping at
+// the first that can't be read or isn't a UF2 block.
+fn scan(
+    sd: &SpiSD<'_>,
+    filename: &str,
+    mut func: impl FnMut(&[u8; UF2_BLOCK_LENGTH], &Block),
+) -> Result<(), Error<SdCardError>> {
+    let mut failure = None;
+    sd.open(filename, |file| {
+        let mut buf: [u8; UF2_BLOCK_LENGTH] = [0; UF2_BLOCK_LENGTH];
+        while !file.is_eof() {
+            if let Err(e) = file.read(&mut buf) {
+                failure = Some(e);
+                return;
+            }
+            let Ok(block) = Block::parse(&buf) else {
+                failure = Some(Error::FormatError("not a UF2 file"));
+                return;
+            };
+            func(&buf, &block);
+        }
+    })?;
+    if let Some(e) = failure {
+        warn!("uf2: can't read {}: {}", filename, e);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// The addresses a block writes, unless its payload is bigger than a block
+// or it runs past the end of memory.
+fn extent(block: &Block) -> Option<Range<u32>> {
+    if block.payload_size as usize > block.data.len() {
+        return None;
+    }
+    let end = block.target_address.checked_add(block.payload_size)?;
+    Some(block.target_address..end)
+}
+
+// Adds the flash sectors a block writes to a sorted list of them.
+fn add_sectors(sectors: &mut Vec<u32>, block: &Block) {
+    let Some(Range { start, end }) = extent(block) else {
+        return;
+    };
+    for sector in start / FLASH_SECTOR_SIZE..end.div_ceil(FLASH_SECTOR_SIZE) {
+        if let Err(index) = sectors.binary_search(&sector) {
+            sectors.insert(index, sector);
+        }
+    }
+}
+
+// Whether a block is for the RP2040, or doesn't say
+fn is_rp2040(raw: &[u8; UF2_BLOCK_LENGTH]) -> bool {
+    word(raw, 8) & FAMILY_ID_PRESENT == 0 || word(raw, 28) == RP2040_FAMILY
+}
+
+fn word(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn overlaps(a: Range<u32>, b: Range<u32>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+/// A name for a UF2 family ID, for the ones the menu might come across.
+pub fn family_name(family: u32) -> Option<&'static str> {
+    match family {
+        0xe48b_ff56 => Some("RP2040"),
+        0xe48b_ff57 => Some("absolute"),
+        0xe48b_ff58 => Some("data"),
+        0xe48b_ff59 => Some("RP2350 ARM-S"),
+        0xe48b_ff5a => Some("RP2350 RISC-V"),
+        0xe48b_ff5b => Some("RP2350 ARM-NS"),
+        _ => None,
+    }
+}
+
+// Whether flashing a block would change anything. Blocks for other chips,
+// or that don't make sense, count as changes rather than being compared.
+fn differs_from_flash(raw: &[u8; UF2_BLOCK_LENGTH], block: &Block) -> bool {
+    if !is_rp2040(raw) {
+        return true;
+    }
+    let Some(payload) = block.data.get(..block.payload_size as usize) else {
+        return true;
+    };
+    let (start, data) = match block.target_address {
+        FLASH_BASE => match payload.get(BOOT2_SIZE as usize..) {
+            Some(data) => (FLASH_BASE + BOOT2_SIZE, data),
+            None => return true,
+        },
+        address => (address, payload),
+    };
+    // Installed apps are read straight from XIP flash
+    match binary_info::xip(start, data.len()) {
+        Some(installed) => installed != data,
+        None => true,
+    }
+}
+
 pub fn read_blocks<'a, F>(sd: &'a SpiSD<'a>, filename: &str, mut func: F)
 where
     F: FnMut(&Block),
@@ -11,7 +238,14 @@ where
     sd.open(filename, |file| {
         while !file.is_eof() {
             let mut buf: [u8; UF2_BLOCK_LENGTH] = [0; UF2_BLOCK_LENGTH];
-            file.read(&mut buf).expect("failed to read");
+            let offset = file.offset();
+            while let Err(e) = file.read(&mut buf) {
+                // Retry the block at a slower clock
+                if !sd.recover(&e) {
+                    core::panic!("failed to read: {:?}", e);
+                }
+                file.seek_from_start(offset).expect("failed to seek");
+            }
             let block = Block::parse(&buf).expect("failed to parse");
             func(&block);
         }
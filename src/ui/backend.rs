@@ -1,16 +1,103 @@
-use alloc::rc::Rc;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::rc::{Rc, Weak};
+use core::cell::{Cell, RefCell};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
 use embassy_time::Instant;
 use slint::{
-    PlatformError,
-    platform::{Platform, WindowAdapter, software_renderer::MinimalSoftwareWindow},
+    EventLoopError, PhysicalSize, PlatformError, Window, WindowSize,
+    platform::{
+        EventLoopProxy, Platform, Renderer, WindowAdapter, WindowEvent,
+        software_renderer::{RepaintBufferType, SoftwareRenderer},
+    },
 };
 
+type Event = Box<dyn FnOnce() + Send>;
+
+// Work posted from other tasks, run by `render_loop` before it draws
+static EVENTS: Mutex<CriticalSectionRawMutex, RefCell<VecDeque<Event>>> =
+    Mutex::new(RefCell::new(VecDeque::new()));
+
+// Wakes `render_loop` when there may be something new to draw
+pub static WAKE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Runs the work posted with `slint::invoke_from_event_loop`, in order.
+pub fn run_events() {
+    while let Some(event) = EVENTS.lock(|events| events.borrow_mut().pop_front()) {
+        event();
+    }
+}
+
+/// Like `MinimalSoftwareWindow`, but a redraw request also wakes the render
+/// loop, so properties set from other tasks reach the display without it
+/// polling.
+pub struct PicoWindow {
+    window: Window,
+    renderer: SoftwareRenderer,
+    needs_redraw: Cell<bool>,
+    size: Cell<PhysicalSize>,
+}
+
+impl PicoWindow {
+    pub fn new(repaint_buffer_type: RepaintBufferType) -> Rc<Self> {
+        Rc::new_cyclic(|adapter: &Weak<Self>| Self {
+            window: Window::new(adapter.clone()),
+            renderer: SoftwareRenderer::new_with_repaint_buffer_type(repaint_buffer_type),
+            needs_redraw: Cell::new(false),
+            size: Cell::new(PhysicalSize::default()),
+        })
+    }
+
+    /// Calls `render` with the renderer if anything has changed since the
+    /// last time, returning whether it did.
+    pub fn draw_if_needed(&self, render: impl FnOnce(&SoftwareRenderer)) -> bool {
+        if !self.needs_redraw.replace(false) {
+            return false;
+        }
+        render(&self.renderer);
+        true
+    }
+
+    pub fn has_active_animations(&self) -> bool {
+        self.window.has_active_animations()
+    }
+}
+
+impl WindowAdapter for PicoWindow {
+    fn window(&self) -> &Window {
+        &self.window
+    }
+
+    fn renderer(&self) -> &dyn Renderer {
+        &self.renderer
+    }
+
+    fn size(&self) -> PhysicalSize {
+        self.size.get()
+    }
+
+    fn set_size(&self, size: WindowSize) {
+        let scale_factor = self.window.scale_factor();
+        self.size.set(size.to_physical(scale_factor));
+        self.window.dispatch_event(WindowEvent::Resized {
+            size: size.to_logical(scale_factor),
+        });
+    }
+
+    fn request_redraw(&self) {
+        self.needs_redraw.set(true);
+        WAKE.signal(());
+    }
+}
+
 pub struct PicoBackend {
-    window: Rc<MinimalSoftwareWindow>,
+    window: Rc<PicoWindow>,
 }
 
 impl PicoBackend {
-    pub fn new(window: Rc<MinimalSoftwareWindow>) -> Self {
+    pub fn new(window: Rc<PicoWindow>) -> Self {
         Self { window }
     }
 }
@@ -24,4 +111,28 @@ impl Platform for PicoBackend {
     fn duration_since_start(&self) -> core::time::Duration {
         Instant::now().duration_since(Instant::from_secs(0)).into()
     }
+
+    // `render_loop` is the event loop, as an embassy task, so that it can
+    // sleep without blocking the others. Nothing should call this.
+    fn run_event_loop(&self) -> Result<(), PlatformError> {
+        Err(PlatformError::Other("render_loop is the event loop".into()))
+    }
+
+    fn new_event_loop_proxy(&self) -> Option<Box<dyn EventLoopProxy>> {
+        Some(Box::new(PicoProxy))
+    }
+}
+
+struct PicoProxy;
+
+impl EventLoopProxy for PicoProxy {
+    fn quit_event_loop(&self) -> Result<(), EventLoopError> {
+        Err(EventLoopError::NoEventLoopProvider)
+    }
+
+    fn invoke_from_event_loop(&self, event: Event) -> Result<(), EventLoopError> {
+        EVENTS.lock(|events| events.borrow_mut().push_back(event));
+        WAKE.signal(());
+        Ok(())
+    }
 }
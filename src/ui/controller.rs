@@ -1,14 +1,68 @@
 use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use defmt::{Display2Format, info, warn};
+use embassy_time::Timer;
+use embedded_sdmmc::{Error, SdCardError};
+use heapless::String;
 use slint::ComponentHandle;
 use slint::Model;
+use slint::ModelRc;
+use slint::SharedString;
+use slint::VecModel;
 use slint::format;
 
 use crate::XIP_BASE;
+use crate::binary_info::{self, BinaryInfo};
 use crate::boot::boot;
+use crate::collection::{self, Collection};
+use crate::display::Geometry;
 use crate::flash::FlashWriter;
+use crate::history::{History, LaunchRecord};
+use crate::rtc;
 use crate::sd::SpiSD;
+use crate::settings::{Autoboot, LineProblem, Problem, Settings, SortOrder, View};
+use crate::sidecar::Sidecars;
+use crate::slint_generatedFileSelector::FileDetails;
 use crate::slint_generatedFileSelector::FileSelector;
-use crate::uf2::read_blocks;
+use crate::slint_generatedFileSelector::FlashConfirmation;
+use crate::slint_generatedFileSelector::ImageDetails;
+use crate::slint_generatedFileSelector::LaunchEntry;
+use crate::slint_generatedFileSelector::Names;
+use crate::slint_generatedFileSelector::PartitionInfo;
+use crate::slint_generatedFileSelector::Screen;
+use crate::slint_generatedFileSelector::SdDiagnostics;
+use crate::slint_generatedFileSelector::Strings;
+use crate::slint_generatedFileSelector::Theme;
+use crate::slint_generatedFileSelector::ThemeName;
+use crate::slint_generatedFileSelector::TitleParts;
+use crate::store::{self, Store};
+use crate::uf2::{self, read_blocks};
+use crate::ui::model::{DirModel, MAX_SORTED, compare_names};
+use crate::{display, set_repeat_timing};
+
+const SEARCH_LENGTH: usize = 32;
+// Displays smaller than this get a smaller header and footer
+const CONDENSED_WIDTH: usize = 240;
+const CONDENSED_HEIGHT: usize = 200;
+// Displays at least this wide get a details column
+const WIDE_WIDTH: usize = 400;
+// Roughly how long erasing and programming a flash sector takes
+const SECTOR_WRITE_MS: u32 = 60;
+// How often to read a queued icon
+const ICON_INTERVAL_MS: u64 = 20;
+// How long the selection has to stay put before its image is read
+const SETTLE_MS: u64 = 250;
+// Images whose binary_info is kept, for moving back and forth
+const MAX_PROGRAMS: usize = 32;
+// Directories whose view is remembered
+const MAX_DIR_VIEWS: usize = 16;
+// Read speed to assume before the card has been measured
+const DEFAULT_READ_BYTES_PER_SEC: u32 = 400 * 1024;
+// Characters kept at the end of a name that's too long for its row, where
+// builds of the same program usually differ
+const TITLE_TAIL: usize = 12;
 
 #[derive(Clone, Copy)]
 pub enum ButtonEvent {
@@ -16,20 +70,111 @@ pub enum ButtonEvent {
     Down,
     Select,
     Refresh,
+    NextView,
+    PageUp,
+    PageDown,
+    NextLetter,
+    Key(char),
+    Backspace,
+    ClearSearch,
+    Pin,
+    Details,
+    ToggleView,
+    // Leaves the current screen, or goes up a directory in the list
+    Back,
 }
 
 pub struct Controller<'spi> {
     ui: &'spi FileSelector,
-    sd: &'spi SpiSD<'spi>,
+    sd: &'static SpiSD<'static>,
+    files: RefCell<Option<Rc<DirModel>>>,
+    search: RefCell<String<SEARCH_LENGTH>>,
+    history: RefCell<History>,
+    settings: RefCell<Settings>,
+    store: RefCell<Store>,
+    // What the display was set up with at boot
+    geometry: Geometry,
+    // Waiting for confirmation before flashing
+    pending: RefCell<Option<PendingLaunch>>,
+    // Shared with the callbacks that give the list its titles and icons
+    sidecars: Rc<RefCell<Sidecars>>,
+    // Runs only while icons are queued
+    icon_timer: Rc<slint::Timer>,
+    // binary_info read from images in the current directory, by name
+    programs: Rc<RefCell<Programs>>,
+    details_timer: Rc<slint::Timer>,
+    // Whether the tabs are the card's top-level folders rather than
+    // sidecar categories
+    folder_tabs: Cell<bool>,
+    // The `.list` file being shown as a folder, if any
+    collection: Rc<RefCell<Option<Collection>>>,
+    // Screens to go back to, below the one showing
+    screens: RefCell<Vec<Screen>>,
+}
+
+type Programs = Vec<(SharedString, Option<BinaryInfo>)>;
+
+struct PendingLaunch {
+    volume: usize,
+    path: alloc::string::String,
+    // Where to go back to if cancelled
+    previous_volume: usize,
 }
 
 impl<'spi> Controller<'spi> {
     pub fn new(
         ui: &'spi FileSelector,
-        sd: &'spi SpiSD<'spi>,
+        sd: &'static SpiSD<'static>,
+        store: Store,
+        geometry: Geometry,
+        // The window's size, which is smaller than the panel when the
+        // framebuffer doesn't fit in RAM
+        window_size: (usize, usize),
     ) -> Result<Self, slint::PlatformError> {
-        let controller = Self { ui, sd };
+        if let Some(brightness) = store.get(&store::BRIGHTNESS) {
+            display::BRIGHTNESS.signal(brightness);
+        }
+        let controller = Self {
+            ui,
+            sd,
+            files: RefCell::new(None),
+            search: RefCell::new(String::new()),
+            history: RefCell::new(History::default()),
+            settings: RefCell::new(Settings::default()),
+            store: RefCell::new(store),
+            geometry,
+            pending: RefCell::new(None),
+            sidecars: Rc::new(RefCell::new(Sidecars::default())),
+            icon_timer: Rc::new(slint::Timer::default()),
+            programs: Rc::new(RefCell::new(Vec::new())),
+            details_timer: Rc::new(slint::Timer::default()),
+            folder_tabs: Cell::new(false),
+            collection: Rc::new(RefCell::new(None)),
+            screens: RefCell::new(Vec::new()),
+        };
         controller.setup_callbacks();
+        controller.apply_theme(&controller.stored_settings());
+        let (width, height) = window_size;
+        controller
+            .ui
+            .global::<Theme>()
+            .set_condensed(width < CONDENSED_WIDTH || height < CONDENSED_HEIGHT);
+        controller.ui.set_wide(width >= WIDE_WIDTH);
+        let (panel_width, panel_height) = geometry.size();
+        if width < panel_width || height < panel_height {
+            let note = controller.strings().invoke_display_cut_down(
+                width as i32,
+                height as i32,
+                panel_width as i32,
+                panel_height as i32,
+            );
+            controller.ui.set_status_message(note.clone());
+            controller.ui.set_display_note(note);
+        }
+        let installed = binary_info::installed().and_then(|info| info.title());
+        controller
+            .ui
+            .set_installed_program(installed.unwrap_or_default().as_str().into());
         Ok(controller)
     }
 
@@ -73,50 +218,1321 @@ impl<'spi> Controller<'spi> {
         self.ui.on_select_file(move || {
             let ui = ui_weak.unwrap();
             let selected = ui.get_selected_file();
-            ui.set_status_message(format!("Selected: {}", selected));
+            ui.set_status_message(ui.global::<Strings>().invoke_selected(selected));
+        });
+
+        // Selection changed callback, for the details column. The image is
+        // only read once the selection settles, so moving through the list
+        // doesn't wait for the card.
+        let ui_weak = self.ui.as_weak();
+        let sd = self.sd;
+        let sidecars = self.sidecars.clone();
+        let programs = self.programs.clone();
+        let details_timer = self.details_timer.clone();
+        self.ui.on_selection_changed(move |name| {
+            let ui = ui_weak.unwrap();
+            show_selection(&ui, sd, &sidecars, &programs, &name, false);
+            let ui_weak = ui.as_weak();
+            let sidecars = sidecars.clone();
+            let programs = programs.clone();
+            details_timer.start(
+                slint::TimerMode::SingleShot,
+                core::time::Duration::from_millis(SETTLE_MS),
+                move || {
+                    let ui = ui_weak.unwrap();
+                    if ui.get_selected_file() == name {
+                        show_selection(&ui, sd, &sidecars, &programs, &name, true);
+                    }
+                },
+            );
+        });
+
+        // Titles and icons for the list, from the sidecar files, with
+        // collections shown as folders
+        let sidecars = self.sidecars.clone();
+        let current = self.collection.clone();
+        self.ui.on_display_title(move |name, _| {
+            if let Some(list) = collection::strip_extension(&name) {
+                return format!("{}/", list);
+            }
+            if let Some(item) = current.borrow().as_ref().and_then(|c| c.find(&name)) {
+                return match &item.title {
+                    Some(title) => title.as_str().into(),
+                    None => name.rsplit('/').next().unwrap_or_default().into(),
+                };
+            }
+            match sidecars
+                .borrow()
+                .get(&name)
+                .and_then(|d| d.title.as_deref())
+            {
+                Some(title) => title.into(),
+                None => name,
+            }
+        });
+        self.ui.on_split_title(split_title);
+        self.ui
+            .global::<Names>()
+            .on_needs_cjk(|name| name.chars().any(is_cjk));
+        let ui_weak = self.ui.as_weak();
+        let sidecars = self.sidecars.clone();
+        let icon_timer = self.icon_timer.clone();
+        self.ui.on_file_icon(move |name, _| {
+            let icon = sidecars.borrow_mut().queued_icon(&name);
+            if sidecars.borrow().has_queued() && !icon_timer.running() {
+                read_queued_icons(&icon_timer, ui_weak.clone(), sidecars.clone(), sd);
+            }
+            icon.unwrap_or_default()
         });
 
         // Refresh callback - this will be handled by the main task
         let ui_weak = self.ui.as_weak();
         self.ui.on_refresh_files(move || {
             let ui = ui_weak.unwrap();
-            ui.set_status_message("Refreshing...".into());
+            ui.set_status_message(ui.global::<Strings>().invoke_refreshing());
         });
     }
 
+    // Moves to the next category or folder tab when there are some, and
+    // re-reads the directory otherwise. Coming back round to "All" re-reads
+    // it too.
+    async fn refresh(&self) {
+        let categories = self.ui.get_categories().row_count();
+        if categories < 2 {
+            return self.refresh_files().await;
+        }
+        let next = (self.ui.get_category_index() as usize + 1) % categories;
+        match self.ui.get_categories().row_data(next) {
+            Some(category) if next > 0 => self.show_category(next, &category).await,
+            _ => self.show_all().await,
+        }
+    }
+
+    /// Re-reads the current directory, keeping the selection.
     pub async fn refresh_files(&self) {
-        self.ui.set_status_message("Loading files...".into());
-        let model = Rc::new(self.sd.list_files());
-        self.ui.set_file_list(model.into());
+        let selected = self.ui.get_selected_file();
+        let collection = self
+            .collection
+            .borrow()
+            .as_ref()
+            .map(|c| c.filename.clone());
+        match collection {
+            Some(filename) => self.open_collection(&filename, Some(&selected)),
+            None => self.load_files(Some(&selected)).await,
+        }
+    }
+
+    // Lists the current directory, selecting the entry called `select` if
+    // there is one.
+    async fn load_files(&self, select: Option<&str>) {
+        self.ui
+            .set_status_message(self.strings().invoke_loading_files());
+        self.collection.replace(None);
+        let sort = self.settings.borrow().sort;
+        let model = match DirModel::new(self.sd, sort) {
+            Ok(model) => Rc::new(model),
+            Err(e) => return self.read_failed(e),
+        };
+        self.ui.set_file_list(model.clone().into());
+        self.files.replace(Some(model.clone()));
+        self.clear_search();
+        self.ui.set_selected_index(0);
+        self.ui.set_current_dir(self.sd.dir_path().as_str().into());
+        self.ui.set_grid(self.dir_view() == View::Grid);
+        self.reset_screens(Screen::Files);
+
+        if let Some(name) = select.filter(|name| !name.is_empty()) {
+            match model.find(0, |n| n.eq_ignore_ascii_case(name)) {
+                Ok(Some(index)) => self.ui.set_selected_index(index as i32),
+                Ok(None) => {}
+                Err(e) => return self.read_failed(e),
+            }
+        }
+        self.load_sidecars();
+        let message = match model.is_sorted(sort) {
+            true => self.strings().invoke_files_loaded(),
+            false => self.strings().invoke_too_many_to_sort(MAX_SORTED as i32),
+        };
+        self.ui.set_status_message(message);
+    }
+
+    fn load_sidecars(&self) {
+        self.sidecars.replace(Sidecars::load(self.sd));
+        self.programs.borrow_mut().clear();
+        // Inside a folder opened from its tab, the card's tabs stay up
+        if !self.folder_tabs.get() || self.ui.get_category_index() == 0 {
+            self.load_tabs();
+        }
+        // The list asks again for titles and icons when this changes
+        self.ui
+            .set_sidecar_generation(self.ui.get_sidecar_generation() + 1);
+        self.ui
+            .invoke_selection_changed(self.ui.get_selected_file());
+    }
+
+    // Tabs for the categories in the directory's descriptors or, at the top
+    // of a card without any, for its folders.
+    fn load_tabs(&self) {
+        let mut tabs = self.sidecars.borrow().categories();
+        let folders = tabs.is_empty() && self.sd.dir_path() == "/";
+        if folders {
+            let listed = self.sd.iterate_files(|_, name| {
+                if let Some(folder) = name.strip_suffix('/').filter(|f| !f.starts_with('.')) {
+                    tabs.push(folder.into());
+                }
+            });
+            if let Err(e) = listed {
+                warn!("failed to list folders: {}", e);
+                tabs.clear();
+            }
+            tabs.sort_by(|a, b| compare_names(a, b, SortOrder::Name));
+        }
+        self.folder_tabs.set(folders && !tabs.is_empty());
+        let model: VecModel<SharedString> = match tabs.is_empty() {
+            true => VecModel::default(),
+            false => core::iter::once(self.strings().invoke_all_categories())
+                .chain(tabs.iter().map(|c| c.as_str().into()))
+                .collect(),
+        };
+        self.ui.set_categories(Rc::new(model).into());
+        self.ui.set_category_index(0);
+    }
+
+    // The entry selected when the menu last left the current directory.
+    fn remembered_selection(&self) -> Option<alloc::string::String> {
+        let store = self.store.borrow();
+        let dir = store.get(&store::LAST_DIR)?;
+        (dir == self.sd.dir_path())
+            .then(|| store.get(&store::LAST_SELECTION))
+            .flatten()
+    }
+
+    fn remember_selection(&self, name: &str) {
+        let mut store = self.store.borrow_mut();
+        store.set(&store::LAST_DIR, &self.sd.dir_path());
+        store.set(&store::LAST_SELECTION, &name.into());
+    }
+
+    // The view chosen for the current directory, or the default.
+    fn dir_view(&self) -> View {
+        let dir = self.sd.dir_path();
+        let views = self
+            .store
+            .borrow()
+            .get(&store::DIR_VIEWS)
+            .unwrap_or_default();
+        for line in views.lines() {
+            match line.split_at_checked(1) {
+                Some(("G", path)) if path == dir => return View::Grid,
+                Some(("L", path)) if path == dir => return View::List,
+                _ => {}
+            }
+        }
+        self.settings.borrow().view
+    }
+
+    fn toggle_view(&self) {
+        let view = match self.dir_view() {
+            View::List => View::Grid,
+            View::Grid => View::List,
+        };
+        let dir = self.sd.dir_path();
+        let mut store = self.store.borrow_mut();
+        let views = store.get(&store::DIR_VIEWS).unwrap_or_default();
+        let mut lines: Vec<&str> = views
+            .lines()
+            .filter(|line| line.get(1..) != Some(dir.as_str()))
+            .collect();
+        let line = alloc::format!("{}{}", if view == View::Grid { 'G' } else { 'L' }, dir);
+        lines.insert(0, &line);
+        lines.truncate(MAX_DIR_VIEWS);
+        // Records are limited in size, so drop the oldest until it fits
+        let mut joined = lines.join("\n");
+        while joined.len() > u8::MAX as usize && lines.len() > 1 {
+            lines.pop();
+            joined = lines.join("\n");
+        }
+        store.set(&store::DIR_VIEWS, &joined);
+        drop(store);
+        self.ui.set_grid(view == View::Grid);
+    }
+
+    // How far a held button moves: a page of the list, or a row of the grid.
+    fn page_step(&self) -> i32 {
+        if self.ui.get_grid() {
+            self.ui.get_grid_columns()
+        } else {
+            self.ui.get_page_rows()
+        }
+    }
+
+    // Settings kept in flash, for the settings file to override.
+    fn stored_settings(&self) -> Settings {
+        let store = self.store.borrow();
+        let mut settings = Settings::default();
+        if let Some(dir) = store.get(&store::LAST_DIR) {
+            settings.start_dir = dir;
+        }
+        if let Some(brightness) = store.get(&store::BRIGHTNESS) {
+            settings.brightness = brightness;
+        }
+        if let Some(autoboot) = store.get(&store::AUTOBOOT) {
+            settings.autoboot = autoboot;
+        }
+        if let Some(theme) = store.get(&store::THEME) {
+            settings.theme = theme;
+        }
+        if let Some(large_text) = store.get(&store::LARGE_TEXT) {
+            settings.large_text = large_text;
+        }
+        if let Some(language) = store.get(&store::LANGUAGE) {
+            settings.language = language;
+        }
+        settings.display_width = self.geometry.width;
+        settings.display_height = self.geometry.height;
+        settings.rotation = self.geometry.rotation;
+        settings
+    }
+
+    fn strings(&self) -> Strings<'_> {
+        self.ui.global::<Strings>()
+    }
+
+    fn apply_theme(&self, settings: &Settings) {
+        let theme = self.ui.global::<Theme>();
+        theme.set_name(match settings.theme.as_str() {
+            "light" => ThemeName::Light,
+            "high-contrast" => ThemeName::HighContrast,
+            _ => ThemeName::Dark,
+        });
+        theme.set_large_text(settings.large_text);
+        theme.set_smooth_scroll(settings.smooth_scroll);
+        if let Err(e) = slint::select_bundled_translation(&settings.language) {
+            warn!(
+                "no translation for {}: {}",
+                settings.language.as_str(),
+                Display2Format(&e)
+            );
+        }
+    }
+
+    fn read_failed(&self, e: Error<SdCardError>) {
+        if self.sd.probe() {
+            self.ui
+                .set_status_message(self.strings().invoke_read_error(format!("{:?}", e)));
+        } else {
+            self.card_removed();
+        }
+    }
+
+    /// Reads the settings and lists the start directory. Returns whether
+    /// there were problems with the settings, which are left on screen.
+    pub async fn card_inserted(&self) -> bool {
+        let label = self.sd.restore_volume();
+        self.ui.set_volume_label(label);
+        let (settings, problems) = Settings::load(self.sd, self.stored_settings());
+        {
+            let mut store = self.store.borrow_mut();
+            store.set(&store::BRIGHTNESS, &settings.brightness);
+            store.set(&store::AUTOBOOT, &settings.autoboot);
+            store.set(&store::THEME, &settings.theme);
+            store.set(&store::LARGE_TEXT, &settings.large_text);
+            store.set(&store::LANGUAGE, &settings.language);
+        }
+        let geometry = Geometry {
+            width: settings.display_width,
+            height: settings.display_height,
+            rotation: settings.rotation,
+        };
+        if geometry != self.geometry {
+            // The framebuffer is sized at boot, so start again
+            info!(
+                "display: now {}x{} at {}°, restarting",
+                geometry.width, geometry.height, geometry.rotation
+            );
+            let mut store = self.store.borrow_mut();
+            store.set(&store::DISPLAY_WIDTH, &geometry.width);
+            store.set(&store::DISPLAY_HEIGHT, &geometry.height);
+            store.set(&store::DISPLAY_ROTATION, &geometry.rotation);
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        self.apply_theme(&settings);
+        display::BRIGHTNESS.signal(settings.brightness);
+        set_repeat_timing(settings.repeat_delay, settings.repeat_rate);
+        let mut problems: Vec<SharedString> = problems.iter().map(|p| self.describe(p)).collect();
+        if let Err(e) = self.sd.set_dir(&settings.start_dir) {
+            problems.push(
+                self.strings()
+                    .invoke_start_dir_error(settings.start_dir.as_str().into(), format!("{:?}", e)),
+            );
+        }
+        self.settings.replace(settings);
+        self.history.replace(History::load(self.sd));
+        self.load_files(self.remembered_selection().as_deref())
+            .await;
+
+        if problems.is_empty() {
+            return false;
+        }
+        let model = VecModel::from(problems);
+        self.ui.set_settings_problems(Rc::new(model).into());
+        self.push_screen(Screen::SettingsErrors);
+        self.ui
+            .set_status_message(self.strings().invoke_settings_problems());
+        true
+    }
+
+    // A problem with the settings file, in the user's language.
+    fn describe(&self, problem: &Problem) -> SharedString {
+        let strings = self.strings();
+        let line_problem = |problem: &LineProblem| match problem {
+            LineProblem::UnclosedSection => strings.invoke_settings_unclosed_section(),
+            LineProblem::NotKeyValue => strings.invoke_settings_not_key_value(),
+            LineProblem::Choice { key, choices } => {
+                strings.invoke_settings_choice(key.as_str().into(), (*choices).into())
+            }
+            LineProblem::Range { key, min, max } => {
+                strings.invoke_settings_range(key.as_str().into(), *min as i32, *max as i32)
+            }
+            LineProblem::UnknownTheme(name) => {
+                strings.invoke_settings_unknown_theme(name.as_str().into())
+            }
+            LineProblem::UnknownLanguage(name) => {
+                strings.invoke_settings_unknown_language(name.as_str().into())
+            }
+            LineProblem::UnknownKey(key) => {
+                strings.invoke_settings_unknown_key(key.as_str().into())
+            }
+        };
+        match problem {
+            Problem::Unreadable(error) => strings.invoke_settings_unreadable(error.as_str().into()),
+            Problem::NotUtf8 => strings.invoke_settings_not_utf8(),
+            Problem::Line(line, problem) => {
+                strings.invoke_settings_line(*line as i32, line_problem(problem))
+            }
+        }
+    }
+
+    /// What to launch without being asked, as a volume and path, and how
+    /// many seconds to wait first.
+    pub fn autoboot_target(&self) -> Option<(usize, alloc::string::String, u32)> {
+        let settings = self.settings.borrow();
+        let (volume, path) = match &settings.autoboot {
+            Autoboot::Off => return None,
+            Autoboot::Last => {
+                let history = self.history.borrow();
+                let last = history.recents().next()?;
+                (last.volume, last.name.clone())
+            }
+            Autoboot::File(path) => (self.sd.volume(), path.clone()),
+        };
+        Some((volume, path, settings.autoboot_delay))
+    }
+
+    pub fn show_autoboot(&self, path: &str, seconds: u32) {
+        self.ui.set_status_message(
+            self.strings()
+                .invoke_autoboot_countdown(path.into(), seconds as i32),
+        );
+    }
+
+    pub fn cancel_autoboot(&self) {
+        self.ui
+            .set_status_message(self.strings().invoke_autoboot_cancelled());
+    }
+
+    pub fn card_removed(&self) {
+        let empty: ModelRc<SharedString> = Rc::new(VecModel::default()).into();
+        self.ui.set_file_list(empty);
+        self.files.replace(None);
+        self.collection.replace(None);
+        self.sidecars.replace(Sidecars::default());
+        self.programs.borrow_mut().clear();
+        self.ui.set_categories(ModelRc::default());
+        self.ui.set_category_index(0);
+        self.folder_tabs.set(false);
+        self.history.replace(History::default());
+        self.clear_search();
         self.ui.set_selected_index(0);
-        self.ui.set_status_message("Files loaded".into());
+        self.reset_screens(Screen::InsertCard);
+        self.ui.set_volume_label(SharedString::new());
+        self.ui.set_current_dir("/".into());
+        self.ui.set_status_message(self.strings().invoke_no_card());
+    }
+
+    pub fn update_clock(&self) {
+        let clock = match rtc::now() {
+            Some(now) => format!("{:02}:{:02}", now.hour, now.minute),
+            None => "--:--".into(),
+        };
+        if self.ui.get_clock() != clock {
+            self.ui.set_clock(clock);
+        }
     }
 
-    pub fn handle_button(&self, button: ButtonEvent) {
+    /// Cycles through the views that sit beside the list: recents,
+    /// favourites, partitions and diagnostics, then back to the list.
+    pub fn next_view(&self) {
+        match self.ui.get_screen() {
+            Screen::Files => self.show_launches(Screen::Recents),
+            Screen::Recents => self.show_launches(Screen::Favourites),
+            Screen::Favourites => {
+                if !self.show_partitions() {
+                    self.show_diagnostics();
+                }
+            }
+            Screen::Partitions => self.show_diagnostics(),
+            Screen::Diagnostics | Screen::SettingsErrors | Screen::FileDetails => self.pop_screen(),
+            Screen::ConfirmFlash => self.cancel_launch(),
+            Screen::InsertCard => {}
+        }
+    }
+
+    /// Shows `screen` over the current one, for Back to return to.
+    fn push_screen(&self, screen: Screen) {
+        let current = self.ui.get_screen();
+        if current != screen {
+            self.screens.borrow_mut().push(current);
+            self.ui.set_screen(screen);
+        }
+    }
+
+    /// Goes back to the screen below the current one, or the list if there
+    /// isn't one.
+    fn pop_screen(&self) {
+        let screen = self.screens.borrow_mut().pop().unwrap_or(Screen::Files);
+        self.ui.set_screen(screen);
+        self.ui.set_status_message("".into());
+    }
+
+    /// Shows `screen` with nothing to go back to, as when a card is
+    /// inserted or removed.
+    fn reset_screens(&self, screen: Screen) {
+        self.screens.borrow_mut().clear();
+        self.ui.set_screen(screen);
+    }
+
+    // The views beside the list are pushed over it, but replace each other
+    // so that Back from any of them goes straight to the list.
+    fn show_view(&self, screen: Screen) {
+        match self.ui.get_screen() {
+            Screen::Files => self.push_screen(screen),
+            _ => self.ui.set_screen(screen),
+        }
+    }
+
+    fn show_launches(&self, screen: Screen) {
+        let history = self.history.borrow();
+        let records: Vec<&LaunchRecord> = match screen {
+            Screen::Favourites => history.favourites().collect(),
+            _ => history.recents().collect(),
+        };
+        let model: VecModel<LaunchEntry> = records
+            .into_iter()
+            .map(|r| LaunchEntry {
+                name: r.name.as_str().into(),
+                volume: r.volume as i32,
+                launches: r.launches as i32,
+                last_launched: match &r.last_launched {
+                    Some(dt) => rtc::format(dt)[..16].into(),
+                    None => SharedString::new(),
+                },
+                pinned: r.pinned,
+            })
+            .collect();
+        let count = model.row_count() as i32;
+        self.ui.set_launches(Rc::new(model).into());
+        self.ui
+            .set_launch_index(self.ui.get_launch_index().clamp(0, (count - 1).max(0)));
+        self.show_view(screen);
+        self.ui.set_status_message("".into());
+    }
+
+    fn move_launch(&self, delta: i32) {
+        let count = self.ui.get_launches().row_count() as i32;
+        if count > 0 {
+            let index = (self.ui.get_launch_index() + delta).rem_euclid(count);
+            self.ui.set_launch_index(index);
+        }
+    }
+
+    fn selected_launch(&self) -> Option<LaunchEntry> {
+        self.ui
+            .get_launches()
+            .row_data(self.ui.get_launch_index() as usize)
+    }
+
+    fn toggle_pin(&self, volume: usize, name: &str) {
+        let mut history = self.history.borrow_mut();
+        let pinned = history.toggle_pin(volume, name);
+        let saved = history.save(self.sd);
+        drop(history);
+        match saved {
+            Ok(()) if pinned => self
+                .ui
+                .set_status_message(self.strings().invoke_favourite_added(name.into())),
+            Ok(()) => self
+                .ui
+                .set_status_message(self.strings().invoke_favourite_removed(name.into())),
+            Err(e) => self.read_failed(e),
+        }
+    }
+
+    // Only worth showing when there's a choice to make.
+    fn show_partitions(&self) -> bool {
+        let partitions = self.sd.partitions();
+        if partitions.len() < 2 {
+            return false;
+        }
+        let current = partitions
+            .iter()
+            .position(|p| p.index == self.sd.volume())
+            .unwrap_or(0);
+        let model: VecModel<PartitionInfo> = partitions
+            .into_iter()
+            .map(|p| PartitionInfo {
+                index: p.index as i32,
+                label: p.label,
+            })
+            .collect();
+        self.ui.set_partitions(Rc::new(model).into());
+        self.ui.set_partition_index(current as i32);
+        self.show_view(Screen::Partitions);
+        self.ui
+            .set_status_message(self.strings().invoke_choose_partition());
+        true
+    }
+
+    fn move_partition(&self, delta: i32) {
+        let count = self.ui.get_partitions().row_count() as i32;
+        if count > 0 {
+            let index = (self.ui.get_partition_index() + delta).rem_euclid(count);
+            self.ui.set_partition_index(index);
+        }
+    }
+
+    async fn choose_partition(&self) {
+        let Some(partition) = self
+            .ui
+            .get_partitions()
+            .row_data(self.ui.get_partition_index() as usize)
+        else {
+            return;
+        };
+        match self.sd.select_volume(partition.index as usize) {
+            Ok(label) => {
+                self.ui.set_volume_label(label);
+                self.load_files(None).await;
+            }
+            Err(e) => {
+                self.ui
+                    .set_status_message(self.strings().invoke_partition_error(format!("{:?}", e)));
+            }
+        }
+    }
+
+    fn show_diagnostics(&self) {
+        self.show_view(Screen::Diagnostics);
+        self.ui
+            .set_status_message(self.strings().invoke_measuring());
+        let stats = match self.sd.benchmark() {
+            Ok(stats) => {
+                self.ui
+                    .set_status_message(self.strings().invoke_diagnostics());
+                stats
+            }
+            Err(e) => {
+                self.ui
+                    .set_status_message(self.strings().invoke_benchmark_failed(format!("{:?}", e)));
+                self.sd.stats()
+            }
+        };
+        self.ui.set_diagnostics(SdDiagnostics {
+            card_mb: (stats.card_size / (1024 * 1024)) as i32,
+            clock_khz: (stats.clock_hz / 1000) as i32,
+            fallbacks: stats.fallbacks as i32,
+            read_kb_per_sec: (stats.read_bytes_per_sec / 1024) as i32,
+        });
+    }
+
+    pub async fn handle_button(&self, button: ButtonEvent) {
+        match (self.ui.get_screen(), button) {
+            (_, ButtonEvent::NextView) => self.next_view(),
+            (Screen::Files, ButtonEvent::Back) => self.leave_dir().await,
+            (Screen::ConfirmFlash, ButtonEvent::Select) => self.confirm_launch(),
+            // Any other button cancels, Back included
+            (Screen::ConfirmFlash, _) => self.cancel_launch(),
+            (Screen::InsertCard, _) => {}
+            (_, ButtonEvent::Back) => self.pop_screen(),
+            // Any button dismisses these
+            (Screen::SettingsErrors | Screen::FileDetails, _) => self.pop_screen(),
+            (Screen::Partitions, button) => self.partitions_button(button).await,
+            (screen @ (Screen::Recents | Screen::Favourites), button) => {
+                self.launches_button(screen, button)
+            }
+            (Screen::Diagnostics, _) => {}
+            (Screen::Files, button) => self.files_button(button).await,
+        }
+    }
+
+    async fn partitions_button(&self, button: ButtonEvent) {
+        match button {
+            ButtonEvent::Up => self.move_partition(-1),
+            ButtonEvent::Down => self.move_partition(1),
+            ButtonEvent::Select => self.choose_partition().await,
+            _ => {}
+        }
+    }
+
+    fn launches_button(&self, screen: Screen, button: ButtonEvent) {
+        match button {
+            ButtonEvent::Up => self.move_launch(-1),
+            ButtonEvent::Down => self.move_launch(1),
+            ButtonEvent::Select => {
+                if let Some(entry) = self.selected_launch() {
+                    self.request_launch(entry.volume as usize, &entry.name);
+                }
+            }
+            ButtonEvent::Pin => {
+                if let Some(entry) = self.selected_launch() {
+                    self.toggle_pin(entry.volume as usize, &entry.name);
+                    self.show_launches(screen);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn files_button(&self, button: ButtonEvent) {
+        match button {
+            ButtonEvent::Key(c) => {
+                let pushed = self.search.borrow_mut().push(c).is_ok();
+                if pushed {
+                    self.run_search();
+                }
+                return;
+            }
+            ButtonEvent::Backspace => {
+                let popped = self.search.borrow_mut().pop().is_some();
+                if popped {
+                    self.run_search();
+                }
+                return;
+            }
+            _ => self.clear_search(),
+        }
         match button {
             ButtonEvent::Up => self.ui.invoke_move_up(),
             ButtonEvent::Down => self.ui.invoke_move_down(),
+            ButtonEvent::PageUp => self.move_by(-self.page_step()),
+            ButtonEvent::PageDown => self.move_by(self.page_step()),
+            ButtonEvent::ToggleView => self.toggle_view(),
+            ButtonEvent::NextLetter => self.next_letter(),
             ButtonEvent::Select => {
-                self.ui.invoke_select_file();
-                self.boot_selected_file();
+                let filename = self.ui.get_selected_file();
+                if self.collection.borrow().is_some() {
+                    // Collections hold paths from the root of the volume
+                    if filename == "../" {
+                        self.close_collection().await;
+                    } else if !filename.is_empty() {
+                        self.request_launch(self.sd.volume(), &filename);
+                    }
+                } else if filename.ends_with('/') {
+                    self.open_dir(&filename).await;
+                } else if collection::strip_extension(&filename).is_some() {
+                    self.remember_selection(&filename);
+                    self.open_collection(&filename, None);
+                } else {
+                    self.ui.invoke_select_file();
+                    self.remember_selection(&filename);
+                    self.request_launch(self.sd.volume(), &self.full_path(&filename));
+                }
+            }
+            ButtonEvent::Refresh => self.refresh().await,
+            ButtonEvent::Details => {
+                let filename = self.ui.get_selected_file();
+                if !filename.is_empty() && !filename.ends_with('/') {
+                    self.show_image_details(&filename).await;
+                }
             }
-            ButtonEvent::Refresh => self.ui.invoke_refresh_files(),
+            ButtonEvent::Pin => {
+                let filename = self.ui.get_selected_file();
+                if !filename.is_empty() && !filename.ends_with('/') {
+                    self.toggle_pin(self.sd.volume(), &self.full_path(&filename));
+                }
+            }
+            _ => {}
         }
     }
 
-    pub fn boot_selected_file(&self) {
-        let filename = self.ui.get_selected_file();
-        if filename.is_empty() {
-            panic!("no selected filename?");
+    // Lists only the files in `category`, which is the `index`th tab. When
+    // the tabs are folders, opens that folder instead.
+    async fn show_category(&self, index: usize, category: &str) {
+        if self.folder_tabs.get() {
+            return self.open_folder_tab(index, category).await;
+        }
+        let mut names: Vec<SharedString> = Vec::new();
+        if self.sd.dir_path() != "/" {
+            names.push("../".into());
+        }
+        let sidecars = self.sidecars.borrow();
+        let listed = self.sd.iterate_files(|_, name| {
+            if sidecars.category(name) == Some(category) {
+                names.push(name.into());
+            }
+        });
+        drop(sidecars);
+        if let Err(e) = listed {
+            return self.read_failed(e);
+        }
+        let sort = self.settings.borrow().sort;
+        if sort != SortOrder::Directory {
+            names.sort_by(|a, b| compare_names(a, b, sort));
+        }
+        self.files.replace(None);
+        self.clear_search();
+        self.ui.set_file_list(Rc::new(VecModel::from(names)).into());
+        self.ui.set_selected_index(0);
+        self.ui.set_category_index(index as i32);
+        self.ui.set_status_message("".into());
+    }
+
+    // Moves from the top of the card, or another folder tab, into `folder`.
+    async fn open_folder_tab(&self, index: usize, folder: &str) {
+        if self.ui.get_category_index() > 0 {
+            if let Err(e) = self.sd.enter_dir("../") {
+                return self.read_failed(e);
+            }
+            self.ui.set_category_index(0);
+        }
+        if let Err(e) = self.sd.enter_dir(&alloc::format!("{}/", folder)) {
+            return self.read_failed(e);
+        }
+        self.ui.set_category_index(index as i32);
+        self.load_files(None).await;
+    }
+
+    // Back to the "All" tab: the whole directory, or the top of the card
+    // when the tabs are its folders.
+    async fn show_all(&self) {
+        if !self.folder_tabs.get() || self.ui.get_category_index() == 0 {
+            return self.refresh_files().await;
+        }
+        self.ui.set_category_index(0);
+        let folder = self.sd.dir_path();
+        let folder = alloc::format!("{}/", folder.trim_matches('/'));
+        if let Err(e) = self.sd.enter_dir("../") {
+            return self.read_failed(e);
+        }
+        self.load_files(Some(&folder)).await;
+    }
+
+    // Shows the images listed in a collection file as if they were a
+    // folder, selecting `select` if it's one of them.
+    fn open_collection(&self, filename: &str, select: Option<&str>) {
+        let collection = match Collection::load(self.sd, filename) {
+            Ok(collection) => collection,
+            Err(e) => {
+                return self.ui.set_status_message(
+                    self.strings()
+                        .invoke_open_error(filename.into(), format!("{:?}", e)),
+                );
+            }
+        };
+        let mut names: Vec<SharedString> = vec!["../".into()];
+        names.extend(
+            collection
+                .items
+                .iter()
+                .map(|item| item.path.as_str().into()),
+        );
+        let index = select
+            .and_then(|select| names.iter().position(|name| name.as_str() == select))
+            .unwrap_or(0);
+        self.ui
+            .set_current_dir(format!("{}{}/", self.sd.dir_path(), collection.name()));
+        self.collection.replace(Some(collection));
+        self.files.replace(None);
+        self.clear_search();
+        self.ui.set_categories(ModelRc::default());
+        self.ui.set_category_index(0);
+        self.ui.set_file_list(Rc::new(VecModel::from(names)).into());
+        self.ui.set_selected_index(index as i32);
+        self.reset_screens(Screen::Files);
+        self.ui
+            .set_sidecar_generation(self.ui.get_sidecar_generation() + 1);
+        self.ui.set_status_message("".into());
+    }
+
+    // Back in the list: out of a collection, back to all categories, or up
+    // a directory.
+    async fn leave_dir(&self) {
+        if self.collection.borrow().is_some() {
+            self.close_collection().await;
+        } else if self.ui.get_category_index() > 0 {
+            self.show_all().await;
+        } else if self.sd.dir_path() != "/" {
+            self.open_dir("../").await;
+        }
+    }
+
+    async fn close_collection(&self) {
+        let Some(collection) = self.collection.take() else {
+            return;
+        };
+        self.load_files(Some(&collection.filename)).await;
+    }
+
+    async fn open_dir(&self, name: &str) {
+        // Going anywhere from the list leaves the folder tabs
+        self.ui.set_category_index(0);
+        // Coming back up, select the directory we were in
+        let came_from = match name {
+            "../" => self
+                .sd
+                .dir_path()
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .map(|d| alloc::format!("{}/", d)),
+            _ => None,
+        };
+        if let Err(e) = self.sd.enter_dir(name) {
+            return self.read_failed(e);
+        }
+        let select = came_from.unwrap_or_default();
+        self.remember_selection(&select);
+        self.load_files(Some(&select)).await;
+    }
+
+    // Moves a page at a time, stopping at the ends rather than wrapping.
+    fn move_by(&self, delta: i32) {
+        let count = self.ui.get_file_list().row_count() as i32;
+        if count > 0 {
+            let index = (self.ui.get_selected_index() + delta).clamp(0, count - 1);
+            self.ui.set_selected_index(index);
+        }
+    }
+
+    fn next_letter(&self) {
+        let Some(files) = self.files.borrow().clone() else {
+            return;
+        };
+        match files.next_initial(self.ui.get_selected_index().max(0) as usize) {
+            Ok(Some(index)) => self.ui.set_selected_index(index as i32),
+            Ok(None) => {}
+            Err(e) => self.read_failed(e),
+        }
+    }
+
+    // Selects the first entry starting with the search text, or failing
+    // that the first containing it.
+    fn run_search(&self) {
+        let search = self.search.borrow().clone();
+        if search.is_empty() {
+            return self.clear_search();
+        }
+        let Some(files) = self.files.borrow().clone() else {
+            return;
+        };
+        // Names starting with the search come before those containing it
+        let found = files.find_best(|name| {
+            match_ignore_case(name, &search).map(|start| usize::from(start != 0))
+        });
+        match found {
+            Ok(Some(index)) => {
+                self.ui.set_selected_index(index as i32);
+                let name = self.ui.get_selected_file();
+                if let Some(start) = match_ignore_case(&name, &search) {
+                    let end = start + search.len();
+                    self.ui.set_search_before(name[..start].into());
+                    self.ui.set_search_match(name[start..end].into());
+                    self.ui.set_search_after(name[end..].into());
+                }
+                self.ui
+                    .set_status_message(self.strings().invoke_search(search.as_str().into()));
+            }
+            Ok(None) => {
+                self.ui.set_search_match(SharedString::new());
+                self.ui.set_status_message(
+                    self.strings()
+                        .invoke_search_no_match(search.as_str().into()),
+                );
+            }
+            Err(e) => self.read_failed(e),
+        }
+    }
+
+    fn clear_search(&self) {
+        if self.search.borrow().is_empty() {
+            return;
+        }
+        self.search.borrow_mut().clear();
+        self.ui.set_search_match(SharedString::new());
+        self.ui.set_status_message("".into());
+    }
+
+    async fn show_image_details(&self, filename: &str) {
+        self.ui
+            .set_status_message(self.strings().invoke_analysing(filename.into()));
+        // Let the message reach the display, as big images take a while
+        Timer::after_millis(50).await;
+        let analysis = match uf2::analyse(self.sd, filename) {
+            Ok(analysis) => analysis,
+            Err(e) => {
+                return self.ui.set_status_message(
+                    self.strings()
+                        .invoke_open_error(filename.into(), format!("{:?}", e)),
+                );
+            }
+        };
+        // Images without binary_info still get the rest
+        let info = binary_info::from_uf2(self.sd, filename)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let descriptor = self
+            .sidecars
+            .borrow()
+            .get(filename)
+            .cloned()
+            .unwrap_or_default();
+        let icon = self
+            .sidecars
+            .borrow_mut()
+            .icon(self.sd, filename)
+            .unwrap_or_default();
+        let mut families = alloc::string::String::new();
+        for family in &analysis.families {
+            if !families.is_empty() {
+                families.push_str(", ");
+            }
+            match uf2::family_name(*family) {
+                Some(name) => families.push_str(name),
+                None => families.push_str(&alloc::format!("{:#010x}", family)),
+            }
+        }
+        self.ui.set_image_details(ImageDetails {
+            name: filename.into(),
+            blocks: analysis.blocks as i32,
+            families: families.as_str().into(),
+            lowest: format!("{:#010x}", analysis.lowest),
+            highest: format!("{:#010x}", analysis.highest),
+            footprint: format_size(analysis.footprint),
+            overlaps_menu: analysis.overlaps_menu,
+            overlaps_config: analysis.overlaps_config,
+            own_boot2: analysis.own_boot2,
+            crc: format!("{:08x}", analysis.crc),
+            program: info.title().unwrap_or_default().as_str().into(),
+            build_date: info.build_date.unwrap_or_default().as_str().into(),
+            url: info.url.unwrap_or_default().as_str().into(),
+            title: descriptor.title.unwrap_or_default().as_str().into(),
+            description: descriptor.description.unwrap_or_default().as_str().into(),
+            byline: [descriptor.author, descriptor.version, descriptor.category]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" • ")
+                .as_str()
+                .into(),
+            icon,
+            pins: Rc::new(
+                info.pins
+                    .iter()
+                    .map(|p| SharedString::from(p.as_str()))
+                    .collect::<VecModel<_>>(),
+            )
+            .into(),
+        });
+        self.push_screen(Screen::FileDetails);
+        self.ui.set_status_message("".into());
+    }
+
+    /// Launches a file once the user has confirmed it, unless the settings
+    /// say not to ask.
+    fn request_launch(&self, volume: usize, path: &str) {
+        if !self.settings.borrow().confirm_flash {
+            return self.launch(volume, path);
+        }
+        let previous_volume = self.sd.volume();
+        if volume != previous_volume {
+            if let Err(e) = self.sd.select_volume(volume) {
+                return self.read_failed(e);
+            }
+        }
+        let summary = match uf2::summarise(self.sd, path) {
+            Ok(summary) => summary,
+            Err(e) => {
+                self.restore_volume(previous_volume);
+                return self.ui.set_status_message(
+                    self.strings()
+                        .invoke_open_error(path.into(), format!("{:?}", e)),
+                );
+            }
+        };
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if summary.protected {
+            self.restore_volume(previous_volume);
+            return self
+                .ui
+                .set_status_message(self.strings().invoke_protected(name.into()));
+        }
+        let bytes = summary.blocks * 512;
+        let read_rate = match self.sd.stats().read_bytes_per_sec {
+            0 => DEFAULT_READ_BYTES_PER_SEC,
+            rate => rate,
+        };
+        let millis = summary.sectors * SECTOR_WRITE_MS + bytes / (read_rate / 1000).max(1);
+        self.ui.set_confirmation(FlashConfirmation {
+            name: name.into(),
+            size: format_size(bytes),
+            sectors: summary.sectors as i32,
+            seconds: millis.div_ceil(1000) as i32,
+            differs: summary.differs,
+        });
+        self.pending.replace(Some(PendingLaunch {
+            volume,
+            path: path.into(),
+            previous_volume,
+        }));
+        self.push_screen(Screen::ConfirmFlash);
+        self.ui
+            .set_status_message(self.strings().invoke_confirm_flash(name.into()));
+    }
+
+    fn confirm_launch(&self) {
+        let Some(pending) = self.pending.take() else {
+            return self.pop_screen();
+        };
+        // Only comes back if the file can't be opened any more, saying why
+        // in the status. It was checked when it was requested.
+        self.flash(&pending.path);
+        let message = self.ui.get_status_message();
+        self.restore_volume(pending.previous_volume);
+        self.pop_screen();
+        self.ui.set_status_message(message);
+    }
+
+    fn cancel_launch(&self) {
+        if let Some(pending) = self.pending.take() {
+            self.restore_volume(pending.previous_volume);
+        }
+        self.pop_screen();
+        self.ui
+            .set_status_message(self.strings().invoke_flash_cancelled());
+    }
+
+    // Goes back to the volume being browsed after looking at another.
+    fn restore_volume(&self, volume: usize) {
+        if volume != self.sd.volume() {
+            if let Err(e) = self.sd.select_volume(volume) {
+                self.read_failed(e);
+            }
+        }
+    }
+
+    /// Launches a file by its path from the root of a volume, switching
+    /// volumes if need be.
+    pub fn launch(&self, volume: usize, path: &str) {
+        if volume != self.sd.volume() {
+            if let Err(e) = self.sd.select_volume(volume) {
+                return self.read_failed(e);
+            }
+        }
+        // Entries can go stale, and a failed open while flashing is fatal
+        match uf2::summarise(self.sd, path) {
+            Ok(summary) if summary.protected => {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                self.ui
+                    .set_status_message(self.strings().invoke_protected(name.into()));
+            }
+            Ok(_) => self.boot_file(path),
+            Err(e) => self.ui.set_status_message(
+                self.strings()
+                    .invoke_open_error(path.into(), format!("{:?}", e)),
+            ),
+        }
+    }
+
+    // Flashes a file that has already been checked, unless it has gone.
+    fn flash(&self, path: &str) {
+        // A failed open while flashing is fatal
+        if let Err(e) = self.sd.open(path, |_| {}) {
+            return self.ui.set_status_message(
+                self.strings()
+                    .invoke_open_error(path.into(), format!("{:?}", e)),
+            );
+        }
+        self.boot_file(path);
+    }
+
+    // Paths are kept from the root of the volume, so they still work once
+    // the menu has moved to another directory.
+    fn full_path(&self, filename: &str) -> alloc::string::String {
+        if filename.starts_with('/') {
+            filename.into()
+        } else {
+            self.sd.dir_path() + filename
+        }
+    }
+
+    fn boot_file(&self, filename: &str) {
+        {
+            let mut history = self.history.borrow_mut();
+            history.record_launch(self.sd.volume(), &self.full_path(filename));
+            if let Err(e) = history.save(self.sd) {
+                warn!("failed to save launch history: {}", e);
+            }
         }
 
         cortex_m::interrupt::disable();
 
         let mut fw = FlashWriter::new();
-        read_blocks(self.sd, &filename, |block| {
+        read_blocks(self.sd, filename, |block| {
             fw.next_block(block);
         });
 
         boot(XIP_BASE + 0x100);
     }
 }
+
+// Byte offset of `needle` in `name`, ignoring ASCII case. Search text is
+// always ASCII, so the match covers `needle.len()` bytes.
+fn match_ignore_case(name: &str, needle: &str) -> Option<usize> {
+    name.char_indices().map(|(start, _)| start).find(|&start| {
+        name.as_bytes()[start..]
+            .get(..needle.len())
+            .is_some_and(|bytes| bytes.eq_ignore_ascii_case(needle.as_bytes()))
+    })
+}
+
+// Splits a title so that the list can elide the middle of it, keeping the
+// end in view.
+fn split_title(title: SharedString) -> TitleParts {
+    let split = title
+        .char_indices()
+        .rev()
+        .nth(TITLE_TAIL - 1)
+        .map_or(0, |(i, _)| i);
+    TitleParts {
+        head: title[..split].into(),
+        tail: title[split..].into(),
+    }
+}
+
+// Whether `c` is in a block that DejaVu Sans doesn't cover but the CJK
+// font does: CJK symbols, kana, ideographs and full-width forms.
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c,
+        '\u{2e80}'..='\u{9fff}' | '\u{f900}'..='\u{faff}' | '\u{ff00}'..='\u{ffef}'
+    )
+}
+
+// Reads the queued icons one per tick between frames, so the list stays
+// responsive while they stream in from the card. The timer stops once the
+// queue is empty, so the render loop can sleep.
+fn read_queued_icons(
+    timer: &Rc<slint::Timer>,
+    ui_weak: slint::Weak<FileSelector>,
+    sidecars: Rc<RefCell<Sidecars>>,
+    sd: &'static SpiSD<'static>,
+) {
+    let this = Rc::downgrade(timer);
+    timer.start(
+        slint::TimerMode::Repeated,
+        core::time::Duration::from_millis(ICON_INTERVAL_MS),
+        move || {
+            if !sidecars.borrow_mut().read_queued(sd) {
+                if let Some(timer) = this.upgrade() {
+                    timer.stop();
+                }
+                return;
+            }
+            let ui = ui_weak.unwrap();
+            ui.set_sidecar_generation(ui.get_sidecar_generation() + 1);
+        },
+    );
+}
+
+// Shows what's known about the selected entry: the program in the selected
+// row, and everything in the details column on wide displays. Only reads
+// the image and its size from the card when `read` is set.
+fn show_selection(
+    ui: &FileSelector,
+    sd: &SpiSD<'_>,
+    sidecars: &RefCell<Sidecars>,
+    programs: &RefCell<Programs>,
+    name: &SharedString,
+    read: bool,
+) {
+    let folder = name.ends_with('/') || name.is_empty();
+    let cached = programs
+        .borrow()
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, program)| program.clone());
+    let program = match (folder, cached) {
+        (true, _) => None,
+        (false, Some(program)) => program,
+        (false, None) if read => {
+            let program = binary_info::from_uf2(sd, name).ok().flatten();
+            let mut programs = programs.borrow_mut();
+            if programs.len() >= MAX_PROGRAMS {
+                programs.remove(0);
+            }
+            programs.push((name.clone(), program.clone()));
+            program
+        }
+        (false, None) => None,
+    };
+    let title: SharedString = program
+        .as_ref()
+        .and_then(BinaryInfo::title)
+        .unwrap_or_default()
+        .as_str()
+        .into();
+    ui.set_selected_program(title.clone());
+    if !ui.get_wide() {
+        return;
+    }
+    let descriptor = sidecars.borrow().get(name).cloned().unwrap_or_default();
+    let size = match folder || !read {
+        true => SharedString::new(),
+        false => sd.file_size(name).map(format_size).unwrap_or_default(),
+    };
+    let icon = match read {
+        true => sidecars.borrow_mut().icon(sd, name),
+        false => sidecars.borrow_mut().queued_icon(name),
+    };
+    ui.set_details(FileDetails {
+        name: descriptor
+            .title
+            .as_deref()
+            .unwrap_or(name.trim_end_matches('/'))
+            .into(),
+        folder,
+        size,
+        program: title,
+        description: descriptor
+            .description
+            .or_else(|| program.and_then(|p| p.description))
+            .unwrap_or_default()
+            .as_str()
+            .into(),
+        author: descriptor.author.unwrap_or_default().as_str().into(),
+        icon: icon.unwrap_or_default(),
+    });
+}
+
+fn format_size(bytes: u32) -> SharedString {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1_048_576 => format!("{}.{} KB", bytes / 1024, bytes % 1024 * 10 / 1024),
+        _ => format!(
+            "{}.{} MB",
+            bytes / 1_048_576,
+            bytes / 1024 % 1024 * 10 / 1024
+        ),
+    }
+}
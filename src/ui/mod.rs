@@ -1,31 +1,76 @@
 use alloc::rc::Rc;
+use embassy_futures::select::{Either3, select3};
+use embassy_futures::yield_now;
 use embassy_time::Timer;
-use slint::platform::software_renderer::{self, MinimalSoftwareWindow};
+use slint::platform::software_renderer;
 
-use crate::display::Display;
+use crate::display::{BRIGHTNESS, Display};
+use crate::ui::backend::PicoWindow;
 
 pub type TargetPixelType = software_renderer::Rgb565Pixel;
 
 pub mod backend;
 pub mod controller;
+pub mod model;
+
+// Longest to sleep with nothing to do, in case a change was missed
+const IDLE_MS: u64 = 1000;
 
 #[embassy_executor::task()]
-pub async fn render_loop(
-    window: Rc<MinimalSoftwareWindow>,
-    display: &'static mut Display<'static>,
-) {
+pub async fn render_loop(window: Rc<PicoWindow>, display: &'static mut Display<'static>) {
     loop {
+        if let Some(percent) = BRIGHTNESS.try_take() {
+            display.set_brightness(percent);
+        }
+
+        backend::run_events();
         slint::platform::update_timers_and_animations();
 
         // blocking render
-        let is_dirty = window.draw_if_needed(|renderer| {
-            renderer.render(display.borrow_framebuffer_mut(), crate::display::WIDTH);
+        #[cfg(not(feature = "line-buffer"))]
+        let drawn = {
+            let (width, _) = display.size();
+            let mut dirty = None;
+            window.draw_if_needed(|renderer| {
+                dirty = Some(renderer.render(display.borrow_framebuffer_mut(), width));
+            });
+
+            // Only what changed goes to the panel
+            if let Some(region) = &dirty {
+                display.draw(region).await.expect("drawing to display");
+            }
+            dirty.is_some()
+        };
+        // Lines go to the panel as they're rendered. Unlike the framebuffer
+        // this doesn't yield while sending, so button and card tasks wait
+        // for the whole frame.
+        #[cfg(feature = "line-buffer")]
+        let drawn = window.draw_if_needed(|renderer| {
+            display.render(renderer).expect("drawing to display");
         });
 
-        if is_dirty {
-            display.draw().await.expect("drawing to display");
-        } else {
-            Timer::after_millis(10).await
+        // Straight on to the next frame, but let the other tasks in first:
+        // rendering by line doesn't yield while it draws
+        if drawn && window.has_active_animations() {
+            yield_now().await;
+            continue;
+        }
+
+        // Sleep until a timer is due or something asks for a redraw
+        let idle = embassy_time::Duration::from_millis(IDLE_MS);
+        let timeout = match slint::platform::duration_until_next_timer_update() {
+            Some(duration) => idle.min(duration.try_into().unwrap_or(idle)),
+            None => idle,
+        };
+        match select3(
+            backend::WAKE.wait(),
+            BRIGHTNESS.wait(),
+            Timer::after(timeout),
+        )
+        .await
+        {
+            Either3::Second(percent) => display.set_brightness(percent),
+            Either3::First(_) | Either3::Third(_) => {}
         }
     }
 }
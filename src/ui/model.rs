@@ -0,0 +1,206 @@
+use core::cell::RefCell;
+use core::cmp::Ordering;
+
+use alloc::vec::Vec;
+use defmt::*;
+use embedded_sdmmc::{Error, SdCardError};
+use slint::{Model, ModelNotify, ModelTracker, SharedString};
+
+use crate::sd::SpiSD;
+use crate::settings::SortOrder;
+
+// Entries held in memory at once, mostly ahead of the row being asked for
+// in the direction the list is moving.
+const PAGE_SIZE: usize = 32;
+// Rows kept behind the one asked for
+const PAGE_CONTEXT: usize = 4;
+
+// Sorting needs the whole directory in memory; bigger ones are listed as
+// stored.
+pub const MAX_SORTED: usize = 512;
+
+struct Page {
+    start: usize,
+    names: Vec<SharedString>,
+}
+
+impl Page {
+    fn get(&self, row: usize) -> Option<&SharedString> {
+        self.names.get(row.checked_sub(self.start)?)
+    }
+}
+
+/// A directory listing that reads names from the card a page at a time,
+/// rather than holding every entry on the heap. Every page costs a walk of
+/// the whole directory, so pages reach ahead of the list.
+pub struct DirModel {
+    sd: &'static SpiSD<'static>,
+    generation: u32,
+    count: usize,
+    // Whether `page` holds the whole directory, sorted
+    sorted: bool,
+    page: RefCell<Page>,
+    notify: ModelNotify,
+}
+
+impl DirModel {
+    /// Reads the first page and counts the entries. Sorted listings are
+    /// read in full, unless there are more than `MAX_SORTED`.
+    pub fn new(sd: &'static SpiSD<'static>, sort: SortOrder) -> Result<Self, Error<SdCardError>> {
+        let max = match sort {
+            SortOrder::Directory => PAGE_SIZE,
+            _ => MAX_SORTED,
+        };
+        let (mut names, count) = sd.read_files(0, max)?;
+        let sorted = sort != SortOrder::Directory && count <= MAX_SORTED;
+        if sorted {
+            names.sort_by(|a, b| compare_names(a, b, sort));
+        } else {
+            names.truncate(PAGE_SIZE);
+        }
+        Ok(Self {
+            sd,
+            generation: sd.generation(),
+            count,
+            sorted,
+            page: RefCell::new(Page { start: 0, names }),
+            notify: ModelNotify::default(),
+        })
+    }
+
+    /// Whether the listing is in the order asked for. Directories too big
+    /// to sort are listed as stored.
+    pub fn is_sorted(&self, sort: SortOrder) -> bool {
+        sort == SortOrder::Directory || self.sorted
+    }
+
+    /// Finds the first entry at or after `from` whose name matches,
+    /// wrapping around to the top of the directory.
+    pub fn find(
+        &self,
+        from: usize,
+        mut matches: impl FnMut(&str) -> bool,
+    ) -> Result<Option<usize>, Error<SdCardError>> {
+        let (mut wrapped, mut found) = (None, None);
+        self.iterate(|index, name| {
+            if found.is_some() || (index < from && wrapped.is_some()) {
+                return;
+            }
+            if matches(name) {
+                if index >= from {
+                    found = Some(index);
+                } else {
+                    wrapped = Some(index);
+                }
+            }
+        })?;
+        Ok(found.or(wrapped))
+    }
+
+    /// Finds the first entry with the best (lowest) rank in one pass,
+    /// skipping those that `rank` gives none. Once an entry ranks 0 the
+    /// rest aren't looked at.
+    pub fn find_best(
+        &self,
+        mut rank: impl FnMut(&str) -> Option<usize>,
+    ) -> Result<Option<usize>, Error<SdCardError>> {
+        let mut best: Option<(usize, usize)> = None;
+        self.iterate(|index, name| {
+            if best.is_some_and(|(r, _)| r == 0) {
+                return;
+            }
+            match rank(name) {
+                Some(r) if best.is_none_or(|(b, _)| r < b) => best = Some((r, index)),
+                _ => {}
+            }
+        })?;
+        Ok(best.map(|(_, index)| index))
+    }
+
+    /// Finds the first entry starting with the next initial letter after
+    /// that of `row`, wrapping around to the lowest one. The directory
+    /// needn't be sorted.
+    pub fn next_initial(&self, row: usize) -> Result<Option<usize>, Error<SdCardError>> {
+        let Some(current) = self.row_data(row).map(|name| initial(&name)) else {
+            return Ok(None);
+        };
+        let (mut next, mut lowest): (Option<(char, usize)>, Option<(char, usize)>) = (None, None);
+        self.iterate(|index, name| {
+            let c = initial(name);
+            if c > current && next.is_none_or(|(n, _)| c < n) {
+                next = Some((c, index));
+            }
+            if lowest.is_none_or(|(l, _)| c < l) {
+                lowest = Some((c, index));
+            }
+        })?;
+        Ok(next.or(lowest).map(|(_, index)| index))
+    }
+
+    // Visits entries in list order.
+    fn iterate(&self, mut func: impl FnMut(usize, &str)) -> Result<(), Error<SdCardError>> {
+        if !self.sorted {
+            return self.sd.iterate_files(func);
+        }
+        for (index, name) in self.page.borrow().names.iter().enumerate() {
+            func(index, name);
+        }
+        Ok(())
+    }
+
+    fn load_page(&self, row: usize) {
+        let start = match row >= self.page.borrow().start {
+            true => row.saturating_sub(PAGE_CONTEXT),
+            false => (row + PAGE_CONTEXT + 1).saturating_sub(PAGE_SIZE),
+        };
+        match self.sd.read_files(start, PAGE_SIZE) {
+            Ok((names, _)) => *self.page.borrow_mut() = Page { start, names },
+            Err(e) => warn!("DirModel: failed to read page at {}: {}", start, e),
+        }
+    }
+}
+
+impl Model for DirModel {
+    type Data = SharedString;
+
+    fn row_count(&self) -> usize {
+        self.count
+    }
+
+    fn row_data(&self, row: usize) -> Option<SharedString> {
+        // Once the card has changed the listing is meaningless
+        if row >= self.count || self.sd.generation() != self.generation {
+            return None;
+        }
+        if let Some(name) = self.page.borrow().get(row) {
+            return Some(name.clone());
+        }
+        self.load_page(row);
+        self.page.borrow().get(row).cloned()
+    }
+
+    fn model_tracker(&self) -> &dyn ModelTracker {
+        &self.notify
+    }
+}
+
+fn initial(name: &str) -> char {
+    name.chars()
+        .next()
+        .and_then(|c| c.to_uppercase().next())
+        .unwrap_or(' ')
+}
+
+// The parent directory first, then other directories, then files, each
+// ordered by name ignoring case.
+pub fn compare_names(a: &str, b: &str, sort: SortOrder) -> Ordering {
+    let group = |name: &str| (name != "../", !name.ends_with('/'));
+    let by_name = a
+        .bytes()
+        .map(|c| c.to_ascii_lowercase())
+        .cmp(b.bytes().map(|c| c.to_ascii_lowercase()));
+    group(a).cmp(&group(b)).then(match sort {
+        SortOrder::NameDescending => by_name.reverse(),
+        _ => by_name,
+    })
+}
@@ -0,0 +1,9 @@
+#!/bin/sh
+# Fetches Noto Sans JP, which covers kana, kanji and most hanzi as well as
+# Latin, for file names in CJK. build.rs embeds only the glyphs listed in
+# MENU_CJK_GLYPHS and cjk-glyphs.txt, not the whole font.
+set -e
+cd "$(dirname "$0")"
+base=https://github.com/notofonts/noto-cjk/raw/main/Sans
+curl -fL -o NotoSansJP-Regular.otf "$base/SubsetOTF/JP/NotoSansJP-Regular.otf"
+curl -fL -o LICENSE-Noto "$base/LICENSE"